@@ -1,50 +1,299 @@
-use anyhow::Context;
-use entity::url;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use echourl::analytics_server::{Analytics, AnalyticsServer};
+use echourl::{DailyClicks, LinkStats, LinkStatsRequest};
 use entity::Expr;
-use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::{ClientConfig, Message};
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use entity::{url, url_click_daily};
+use metrics::{counter, gauge};
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, StreamConsumer};
+use rdkafka::message::BorrowedMessage;
+use rdkafka::message::Headers;
+use rdkafka::statistics::Statistics;
+use rdkafka::{ClientConfig, ClientContext, Message};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter, QueryOrder, Set,
+    TransactionTrait,
+};
+use shared::config::{Config, ServiceDefaults};
+use shared::health::{self, Readiness};
+use shared::prometheus::{self, timed};
+use shared::shutdown::Shutdown;
+use shared::telemetry;
+use shared::trace_context::{self, TraceContext};
 use shared::{connect_db, DbPool};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_stream::StreamExt;
-use tracing::{error, info};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, info_span, warn, Instrument};
+
+mod echourl {
+    tonic::include_proto!("echourl");
+}
+
+const DEFAULT_STATS_DAYS: u32 = 30;
+const MAX_STATS_DAYS: u32 = 365;
+const RETRY_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(10);
 
 #[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+async fn main() -> Result<()> {
+    let config = Config::load(&ServiceDefaults {
+        name: "analytics_service",
+        listen_addr: "0.0.0.0:50052",
+        metrics_addr: "0.0.0.0:9102",
+    })?;
+    let _telemetry =
+        telemetry::init(&config.telemetry).context("Failed to initialise telemetry")?;
+    let metrics = prometheus::install()?;
+    let shutdown = Shutdown::install(config.server.shutdown_grace_period());
+    let metrics_addr = config.server.metrics_addr;
 
-    let db = connect_db()
+    let db = connect_db(&config.database)
         .await
-        .context("Database connection failed")
-        .unwrap();
+        .context("Database connection failed")?;
 
-    let db = Arc::new(db);
+    let addr = config.server.listen_addr;
+    let service = AnalyticsService { db: db.clone() };
+    info!("🚀 gRPC server listening on {}", addr);
+
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    health
+        .set_serving::<AnalyticsServer<AnalyticsService>>()
+        .await;
+
+    let consumer: Arc<StreamConsumer<LagReporter>> = Arc::new(
+        ClientConfig::new()
+            .set("bootstrap.servers", &config.kafka.brokers)
+            .set("group.id", &config.kafka.consumer_group)
+            .set("auto.offset.reset", "earliest")
+            // Offsets are stored once a click has been handled and committed in the background.
+            .set("enable.auto.offset.store", "false")
+            .set("statistics.interval.ms", "15000")
+            .create_with_context(LagReporter)
+            .context("Kafka consumer creation failed")?,
+    );
+    consumer
+        .subscribe(&[&config.kafka.clicks_topic])
+        .context("Failed to subscribe")?;
 
-    consume_clicks(db).await;
+    let readiness = Readiness::new()
+        .draining(&shutdown)
+        .postgres(db.clone())
+        .check("kafka", {
+            let consumer = consumer.clone();
+            let topic = config.kafka.clicks_topic.clone();
+            move || kafka_ready(consumer.clone(), topic.clone())
+        });
+    let admin = prometheus::router(metrics).merge(health::router(readiness));
+
+    let server = Server::builder()
+        .layer(
+            TraceLayer::new_for_grpc()
+                .make_span_with(trace_context::grpc_span)
+                .on_response(telemetry::on_grpc_response),
+        )
+        .add_service(health_service)
+        .add_service(AnalyticsServer::new(service))
+        .serve_with_shutdown(addr, shutdown.requested());
+
+    tokio::try_join!(
+        shutdown.drain("gRPC server", async {
+            server.await.context("gRPC server error")
+        }),
+        shutdown.drain(
+            "Metrics server",
+            prometheus::serve(metrics_addr, admin, &shutdown)
+        ),
+        shutdown.drain("Click consumer", consume_clicks(&db, consumer, &shutdown)),
+    )?;
+    Ok(())
 }
 
-async fn consume_clicks(db: Arc<DbPool>) {
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        .set("group.id", "analytics_group")
-        .set("auto.offset.reset", "earliest")
-        .create()
-        .expect("Kafka consumer creation failed");
+struct AnalyticsService {
+    db: DbPool,
+}
 
-    consumer
-        .subscribe(&["url_clicks"])
-        .expect("Failed to subscribe");
+#[tonic::async_trait]
+impl Analytics for AnalyticsService {
+    async fn get_link_stats(
+        &self,
+        request: Request<LinkStatsRequest>,
+    ) -> Result<Response<LinkStats>, Status> {
+        let LinkStatsRequest { slug, days } = request.into_inner();
+        let days = match days {
+            0 => DEFAULT_STATS_DAYS,
+            days => days.min(MAX_STATS_DAYS),
+        };
+
+        let url_entry = timed(
+            "find_by_slug",
+            url::Entity::find()
+                .filter(url::Column::Shortened.eq(&slug))
+                .one(&*self.db),
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .ok_or_else(|| Status::not_found("URL not found"))?;
+
+        let since = Utc::now().date_naive() - Days::new(u64::from(days) - 1);
+        let daily = timed(
+            "daily_clicks",
+            url_click_daily::Entity::find()
+                .filter(url_click_daily::Column::Slug.eq(&slug))
+                .filter(url_click_daily::Column::Day.gte(since))
+                .order_by_asc(url_click_daily::Column::Day)
+                .all(&*self.db),
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(LinkStats {
+            slug,
+            total_clicks: url_entry.clicks,
+            daily: daily
+                .into_iter()
+                .map(|row| DailyClicks {
+                    date: row.day.to_string(),
+                    clicks: row.clicks,
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// Publishes the consumer lag librdkafka reports in its periodic statistics.
+struct LagReporter;
+
+impl ClientContext for LagReporter {
+    fn stats(&self, statistics: Statistics) {
+        for topic in statistics.topics.values() {
+            // Lag is -1 until an offset is known; partition -1 is librdkafka's internal one.
+            for partition in topic.partitions.values() {
+                if partition.partition >= 0 && partition.consumer_lag >= 0 {
+                    gauge!(
+                        "kafka_consumer_lag",
+                        "topic" => topic.topic.clone(),
+                        "partition" => partition.partition.to_string(),
+                    )
+                    .set(partition.consumer_lag as f64);
+                }
+            }
+        }
+    }
+}
+
+impl ConsumerContext for LagReporter {}
 
+/// Metadata requests block, so they run off the async workers.
+async fn kafka_ready(consumer: Arc<StreamConsumer<LagReporter>>, topic: String) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        consumer
+            .fetch_metadata(Some(&topic), Duration::from_secs(1))
+            .map(|_| ())
+    })
+    .await?
+    .context("Kafka metadata request failed")
+}
+
+async fn consume_clicks(
+    db: &DbPool,
+    consumer: Arc<StreamConsumer<LagReporter>>,
+    shutdown: &Shutdown,
+) -> Result<()> {
     let mut message_stream = consumer.stream();
-    while let Some(Ok(message)) = message_stream.next().await {
-        if let Some(payload) = message.payload_view::<str>() {
-            if let Ok(payload) = payload {
-                if let Some(slug) = extract_slug(payload) {
-                    increment_click_count(&db, &slug).await;
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.requested() => break,
+            message = message_stream.next() => match message {
+                Some(Ok(message)) => message,
+                // librdkafka recovers from broker and partition errors by itself.
+                Some(Err(e)) => {
+                    error!("Kafka consumer error: {:?}", e);
+                    counter!("kafka_consumer_errors_total").increment(1);
+                    continue;
                 }
+                None => break,
+            },
+        };
+
+        // The offset only moves past a click once it is recorded, so a failing database
+        // holds up consumption instead of losing clicks.
+        let mut backoff = RETRY_BACKOFF_MIN;
+        while let Err(e) = record_click(db, &message).await {
+            error!("Failed to record click, retrying in {:?}: {:?}", backoff, e);
+            counter!("clicks_failed_total", "reason" => "database").increment(1);
+            tokio::select! {
+                _ = shutdown.requested() => return commit(&consumer),
+                _ = tokio::time::sleep(backoff) => {}
             }
+            backoff = (backoff * 2).min(RETRY_BACKOFF_MAX);
         }
+        if let Err(e) = consumer.store_offset_from_message(&message) {
+            warn!("Failed to store offset: {:?}", e);
+        }
+    }
+
+    commit(&consumer)
+}
+
+/// Commits what has been handled so far so that a restart does not replay it.
+fn commit(consumer: &StreamConsumer<LagReporter>) -> Result<()> {
+    consumer
+        .commit_consumer_state(CommitMode::Sync)
+        .context("Failed to commit consumer offsets")
+}
+
+/// Counts a click in one transaction, so that a retry does not count it twice. Messages
+/// without a slug are skipped.
+async fn record_click(
+    db: &sea_orm::DatabaseConnection,
+    message: &BorrowedMessage<'_>,
+) -> Result<(), DbErr> {
+    let Some(slug) = message
+        .payload_view::<str>()
+        .and_then(Result::ok)
+        .and_then(extract_slug)
+    else {
+        counter!("clicks_failed_total", "reason" => "invalid_payload").increment(1);
+        return Ok(());
+    };
+
+    let day = message
+        .timestamp()
+        .to_millis()
+        .and_then(DateTime::from_timestamp_millis)
+        .unwrap_or_else(Utc::now)
+        .date_naive();
+
+    let context = TraceContext::extract(|name| {
+        message.headers()?.iter().find_map(|header| {
+            (header.key == name)
+                .then(|| std::str::from_utf8(header.value?).ok())
+                .flatten()
+        })
+    });
+    let span = info_span!(
+        "click",
+        slug = %slug,
+        request_id = %context.request_id,
+        trace_id = %context.trace_id,
+    );
+    context.link(&span);
+    async {
+        let txn = db.begin().await?;
+        increment_click_count(&txn, &slug).await?;
+        increment_daily_clicks(&txn, &slug, day).await?;
+        txn.commit().await
     }
+    .instrument(span)
+    .await?;
+
+    counter!("clicks_processed_total").increment(1);
+    Ok(())
 }
 
 fn extract_slug(payload: &str) -> Option<String> {
@@ -52,15 +301,45 @@ fn extract_slug(payload: &str) -> Option<String> {
     json.get("slug")?.as_str().map(|s| s.to_string())
 }
 
-async fn increment_click_count(db: &sea_orm::DatabaseConnection, slug: &str) {
-    if let Err(e) = url::Entity::update_many()
-        .filter(url::Column::Shortened.eq(slug))
-        .col_expr(url::Column::Clicks, Expr::col(url::Column::Clicks).add(1))
-        .exec(db)
-        .await
-    {
-        error!("Failed to increment click count for `{}`: {:?}", slug, e);
-    } else {
-        info!("Incremented click count for `{}`", slug);
-    }
+async fn increment_click_count(db: &impl ConnectionTrait, slug: &str) -> Result<(), DbErr> {
+    timed(
+        "increment_clicks",
+        url::Entity::update_many()
+            .filter(url::Column::Shortened.eq(slug))
+            .col_expr(url::Column::Clicks, Expr::col(url::Column::Clicks).add(1))
+            .exec(db),
+    )
+    .await?;
+    info!("Incremented click count for `{}`", slug);
+    Ok(())
+}
+
+async fn increment_daily_clicks(
+    db: &impl ConnectionTrait,
+    slug: &str,
+    day: NaiveDate,
+) -> Result<(), DbErr> {
+    let row = url_click_daily::ActiveModel {
+        slug: Set(slug.to_string()),
+        day: Set(day),
+        clicks: Set(1),
+        ..Default::default()
+    };
+
+    timed(
+        "increment_daily_clicks",
+        url_click_daily::Entity::insert(row)
+            .on_conflict(
+                OnConflict::columns([url_click_daily::Column::Slug, url_click_daily::Column::Day])
+                    .value(
+                        url_click_daily::Column::Clicks,
+                        Expr::col((url_click_daily::Entity, url_click_daily::Column::Clicks))
+                            .add(1),
+                    )
+                    .to_owned(),
+            )
+            .exec_without_returning(db),
+    )
+    .await?;
+    Ok(())
 }
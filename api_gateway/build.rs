@@ -1,4 +1,8 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../proto/url.proto")?;
+    // Both files share the `echourl` package, so they must be compiled together.
+    tonic_build::configure().compile_protos(
+        &["../proto/url.proto", "../proto/analytics.proto"],
+        &["../proto"],
+    )?;
     Ok(())
 }
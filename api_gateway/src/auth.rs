@@ -0,0 +1,240 @@
+use crate::error::ApiError;
+use anyhow::{bail, Context, Result};
+use axum::extract::{Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shared::identity::{ForwardedIdentity, IdentitySigner};
+use shared::rate_limit::ClientKey;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+use tonic::metadata::MetadataValue;
+use tonic::Request as GrpcRequest;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing bearer token")]
+    MissingToken,
+
+    #[error("Invalid bearer token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+
+    #[error("No verification key for token (alg {0:?}, kid {1:?})")]
+    UnknownKey(Algorithm, Option<String>),
+
+    #[error("Token is missing the `{0}` claim")]
+    MissingClaim(String),
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        warn!("Rejected request: {}", self);
+        ApiError::from(self).into_response()
+    }
+}
+
+/// Caller resolved from a verified bearer token.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub user_id: String,
+    /// Signed metadata vouching for the identity to the backends.
+    forwarded: Vec<(&'static str, String)>,
+}
+
+impl Identity {
+    /// Attaches the signed identity to an outgoing gRPC request as metadata.
+    pub fn apply<T>(&self, request: &mut GrpcRequest<T>) {
+        let metadata = request.metadata_mut();
+        for (name, value) in &self.forwarded {
+            if let Ok(value) = MetadataValue::try_from(value.as_str()) {
+                metadata.insert(*name, value);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub hs256_secret: Option<String>,
+    pub jwks_path: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub user_claim: String,
+    pub required: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            hs256_secret: None,
+            jwks_path: None,
+            issuer: None,
+            audience: None,
+            user_claim: "sub".to_string(),
+            required: false,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        if self.required && self.hs256_secret.is_none() && self.jwks_path.is_none() {
+            errors.push(
+                "gateway.auth.required is set but neither hs256_secret nor jwks_path is configured"
+                    .to_string(),
+            );
+        }
+        if self.user_claim.is_empty() {
+            errors.push("gateway.auth.user_claim must not be empty".to_string());
+        }
+    }
+}
+
+pub struct JwtVerifier {
+    hs256: Option<DecodingKey>,
+    keys: HashMap<Option<String>, (Algorithm, DecodingKey)>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    user_claim: String,
+    required: bool,
+    signer: IdentitySigner,
+}
+
+impl JwtVerifier {
+    pub fn new(config: &AuthConfig, signer: IdentitySigner) -> Result<Self> {
+        if config.required && config.hs256_secret.is_none() && config.jwks_path.is_none() {
+            bail!("Authentication is required but no HS256 secret or JWKS file is configured");
+        }
+
+        let hs256 = config
+            .hs256_secret
+            .as_deref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+
+        let mut keys = HashMap::new();
+        if let Some(path) = &config.jwks_path {
+            let raw = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read JWKS file `{}`", path))?;
+            let jwks: JwkSet = serde_json::from_str(&raw).context("Failed to parse JWKS file")?;
+
+            for jwk in &jwks.keys {
+                let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+                    (Some(KeyAlgorithm::RS256), _) => Algorithm::RS256,
+                    (Some(KeyAlgorithm::ES256), _) => Algorithm::ES256,
+                    (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+                    (None, AlgorithmParameters::EllipticCurve(ec))
+                        if ec.curve == EllipticCurve::P256 =>
+                    {
+                        Algorithm::ES256
+                    }
+                    _ => {
+                        warn!("Skipping unsupported JWKS key {:?}", jwk.common.key_id);
+                        continue;
+                    }
+                };
+                let key = DecodingKey::from_jwk(jwk).context("Invalid key in JWKS file")?;
+                keys.insert(jwk.common.key_id.clone(), (algorithm, key));
+            }
+            info!("Loaded {} key(s) from JWKS file `{}`", keys.len(), path);
+        }
+
+        Ok(Self {
+            hs256,
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            user_claim: config.user_claim.clone(),
+            required: config.required,
+            signer,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.hs256.is_some() || !self.keys.is_empty()
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        let header = decode_header(token)?;
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256.as_ref(),
+            Algorithm::RS256 | Algorithm::ES256 => self.find_key(header.alg, &header.kid),
+            _ => None,
+        }
+        .ok_or_else(|| AuthError::UnknownKey(header.alg, header.kid.clone()))?;
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let claims = decode::<HashMap<String, Value>>(token, key, &validation)?.claims;
+        let user_id = claims
+            .get(&self.user_claim)
+            .and_then(|value| match value {
+                Value::String(s) => Some(s.clone()),
+                Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            })
+            .ok_or_else(|| AuthError::MissingClaim(self.user_claim.clone()))?;
+        let issuer = claims
+            .get("iss")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        let forwarded = self.signer.sign(&ForwardedIdentity {
+            user_id: user_id.clone(),
+            issuer,
+        });
+        Ok(Identity { user_id, forwarded })
+    }
+
+    fn find_key(&self, algorithm: Algorithm, kid: &Option<String>) -> Option<&DecodingKey> {
+        if let Some((alg, key)) = self.keys.get(kid) {
+            return (*alg == algorithm).then_some(key);
+        }
+
+        // Tokens without a `kid` are accepted only when a single key could have signed them.
+        let mut candidates = self.keys.values().filter(|(alg, _)| *alg == algorithm);
+        match (kid, candidates.next(), candidates.next()) {
+            (None, Some((_, key)), None) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+pub async fn authenticate(
+    State(verifier): State<Arc<JwtVerifier>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if verifier.is_enabled() => {
+            let identity = verifier.verify(token.trim())?;
+            request
+                .extensions_mut()
+                .insert(ClientKey(identity.user_id.clone()));
+            request.extensions_mut().insert(identity);
+        }
+        _ if verifier.required => return Err(AuthError::MissingToken),
+        _ => {}
+    }
+
+    Ok(next.run(request).await)
+}
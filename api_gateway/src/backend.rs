@@ -0,0 +1,551 @@
+use crate::auth::Identity;
+use crate::discovery;
+use anyhow::{bail, Result};
+use metrics::counter;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use shared::trace_context::TraceContext;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tonic::transport::{Channel, Uri};
+use tonic::{Code, Request, Response, Status};
+use tracing::warn;
+
+const BALANCE_CHANNEL_CAPACITY: usize = 64;
+
+/// Whether an RPC can safely be sent again after a failure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retry {
+    Idempotent,
+    Never,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BackendConfig {
+    /// Statically configured instances.
+    pub endpoints: Vec<String>,
+    /// URL whose host is re-resolved to discover instances.
+    pub dns: Option<String>,
+    pub dns_refresh_ms: u64,
+    pub health_check_interval_ms: u64,
+    pub connect_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+    /// Consecutive failures that open the circuit breaker.
+    pub failure_threshold: u32,
+    pub open_duration_ms: u64,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            dns: None,
+            dns_refresh_ms: 30_000,
+            health_check_interval_ms: 5_000,
+            connect_timeout_ms: 1_000,
+            request_timeout_ms: 3_000,
+            max_retries: 2,
+            retry_backoff_ms: 50,
+            failure_threshold: 5,
+            open_duration_ms: 10_000,
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn with_endpoint(endpoint: &str) -> Self {
+        Self {
+            endpoints: vec![endpoint.to_string()],
+            ..Self::default()
+        }
+    }
+
+    pub fn validate(&self, name: &str, errors: &mut Vec<String>) {
+        if self.endpoints.is_empty() && self.dns.is_none() {
+            errors.push(format!("gateway.{}: set `endpoints` or `dns`", name));
+        }
+        for uri in self.endpoints.iter().chain(&self.dns) {
+            if uri.parse::<Uri>().is_err() {
+                errors.push(format!("gateway.{}: invalid endpoint `{}`", name, uri));
+            }
+        }
+        if self.health_check_interval_ms == 0 || self.request_timeout_ms == 0 {
+            errors.push(format!(
+                "gateway.{}: health_check_interval_ms and request_timeout_ms must be greater than zero",
+                name
+            ));
+        }
+    }
+
+    pub fn dns_refresh(&self) -> Duration {
+        Duration::from_millis(self.dns_refresh_ms)
+    }
+
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_millis(self.health_check_interval_ms)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+}
+
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probe_started: Instant },
+}
+
+/// Fails fast while a backend keeps failing; after `open_duration` a single probe request
+/// decides whether to close the circuit again.
+struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl CircuitBreaker {
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let probe = match *state {
+            BreakerState::Closed { .. } => return true,
+            BreakerState::Open { until } => now >= until,
+            // A probe that never reported back (e.g. the client went away) is retried.
+            BreakerState::HalfOpen { probe_started } => {
+                now.duration_since(probe_started) >= self.open_duration
+            }
+        };
+        if probe {
+            *state = BreakerState::HalfOpen { probe_started: now };
+        }
+        probe
+    }
+
+    fn record(&self, healthy: bool) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = match (&*state, healthy) {
+            (_, true) => BreakerState::Closed { failures: 0 },
+            (BreakerState::Closed { failures }, false) if failures + 1 < self.failure_threshold => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => BreakerState::Open {
+                until: Instant::now() + self.open_duration,
+            },
+        };
+    }
+}
+
+/// Channel balanced across the healthy instances of a backend, with per-call deadlines,
+/// retries and a circuit breaker.
+#[derive(Clone)]
+pub struct Backend {
+    name: &'static str,
+    channel: Channel,
+    healthy: Arc<AtomicUsize>,
+    /// Whether discovery has probed every instance at least once.
+    discovered: watch::Receiver<bool>,
+    breaker: Arc<CircuitBreaker>,
+    config: Arc<BackendConfig>,
+}
+
+impl Backend {
+    /// Does not wait for the backend; instances join the channel as soon as they pass a health
+    /// check and are ejected while they fail it.
+    pub fn connect_lazy(name: &'static str, config: BackendConfig) -> Result<Self> {
+        if config.endpoints.is_empty() && config.dns.is_none() {
+            bail!("No {} endpoints configured", name);
+        }
+        for uri in &config.endpoints {
+            discovery::endpoint(name, &config, uri)?;
+        }
+
+        let (channel, changes) = Channel::balance_channel(BALANCE_CHANNEL_CAPACITY);
+        let healthy = Arc::new(AtomicUsize::new(0));
+        let (discovered_tx, discovered) = watch::channel(false);
+        let config = Arc::new(config);
+        tokio::spawn(discovery::run(
+            name,
+            config.clone(),
+            changes,
+            healthy.clone(),
+            discovered_tx,
+        ));
+
+        Ok(Self {
+            name,
+            channel,
+            healthy,
+            discovered,
+            breaker: Arc::new(CircuitBreaker {
+                state: Mutex::new(BreakerState::Closed { failures: 0 }),
+                failure_threshold: config.failure_threshold.max(1),
+                open_duration: Duration::from_millis(config.open_duration_ms),
+            }),
+            config,
+        })
+    }
+
+    pub fn healthy_instances(&self) -> usize {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Builds a request carrying the caller identity, the current trace context and a
+    /// `grpc-timeout`, so that the backend stops working on it once the gateway gives up.
+    pub fn request<T>(&self, message: T, identity: Option<&Identity>) -> Request<T> {
+        let mut request = Request::new(message);
+        request.set_timeout(self.config.request_timeout());
+        if let Some(context) = TraceContext::current() {
+            for (name, value) in context.headers() {
+                if let Ok(value) = value.parse() {
+                    request.metadata_mut().insert(name, value);
+                }
+            }
+        }
+        if let Some(identity) = identity {
+            identity.apply(&mut request);
+        }
+        request
+    }
+
+    /// Runs `rpc` against the backend. The closure is invoked once per attempt, so it must
+    /// build a fresh request each time; the circuit breaker counts the call as a whole.
+    pub async fn call<T, F, Fut>(&self, retry: Retry, rpc: F) -> Result<Response<T>, Status>
+    where
+        F: FnMut(Channel) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        if self.healthy.load(Ordering::Relaxed) == 0 {
+            self.wait_for_discovery().await;
+        }
+        if self.healthy.load(Ordering::Relaxed) == 0 {
+            return Err(Status::unavailable(format!(
+                "No healthy {} instances",
+                self.name
+            )));
+        }
+        if !self.breaker.try_acquire() {
+            counter!("grpc_client_circuit_open_total", "backend" => self.name).increment(1);
+            return Err(Status::unavailable(format!(
+                "{} is unavailable (circuit open)",
+                self.name
+            )));
+        }
+
+        let result = self.attempt(retry, rpc).await;
+        // Application errors such as NotFound mean the backend itself is healthy.
+        self.breaker.record(
+            result
+                .as_ref()
+                .err()
+                .is_none_or(|status| !is_transient(status)),
+        );
+        self.record_outcome(result.as_ref().map_or_else(Status::code, |_| Code::Ok));
+        result
+    }
+
+    /// Calls made right after startup wait, at most for the request timeout, for the first
+    /// round of health checks rather than failing before any instance has been probed.
+    async fn wait_for_discovery(&self) {
+        let mut discovered = self.discovered.clone();
+        let _ = tokio::time::timeout(
+            self.config.request_timeout(),
+            discovered.wait_for(|discovered| *discovered),
+        )
+        .await;
+    }
+
+    /// Sends `rpc`, retrying transient failures when `retry` allows it.
+    async fn attempt<T, F, Fut>(&self, retry: Retry, mut rpc: F) -> Result<Response<T>, Status>
+    where
+        F: FnMut(Channel) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let attempts = match retry {
+            Retry::Idempotent => self.config.max_retries + 1,
+            Retry::Never => 1,
+        };
+
+        let mut attempt = 0;
+        loop {
+            let result = match tokio::time::timeout(
+                self.config.request_timeout(),
+                rpc(self.channel.clone()),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(Status::deadline_exceeded(format!(
+                    "{} did not respond within {:?}",
+                    self.name,
+                    self.config.request_timeout()
+                ))),
+            };
+
+            let status = match result {
+                Ok(response) => return Ok(response),
+                Err(status) => status,
+            };
+
+            attempt += 1;
+            if !is_transient(&status) || attempt >= attempts {
+                return Err(status);
+            }
+            counter!("grpc_client_retries_total", "backend" => self.name).increment(1);
+
+            let backoff =
+                Duration::from_millis(self.config.retry_backoff_ms) * 2u32.pow(attempt - 1);
+            let jitter = rand::rng().random_range(0..=backoff.as_millis() as u64 / 2);
+            warn!(
+                "{} call failed ({}), retrying in {:?}",
+                self.name,
+                status.code(),
+                backoff
+            );
+            tokio::time::sleep(backoff + Duration::from_millis(jitter)).await;
+        }
+    }
+
+    fn record_outcome(&self, code: Code) {
+        counter!(
+            "grpc_client_requests_total",
+            "backend" => self.name,
+            "code" => format!("{:?}", code),
+        )
+        .increment(1);
+    }
+}
+
+fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::Unknown
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echourl::shorten_url_client::ShortenUrlClient;
+    use crate::echourl::shorten_url_server::{ShortenUrl, ShortenUrlServer};
+    use crate::echourl::{
+        DeleteResponse, ListUrlsRequest, ListUrlsResponse, OriginalUrl, ShortenedUrl, Slug,
+        UpdateUrlRequest,
+    };
+    use tokio::net::TcpListener;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic_health::server::HealthReporter;
+    use tonic_health::ServingStatus;
+
+    /// Counts the lookups one instance receives; nothing else is called.
+    struct CountingShortener(Arc<AtomicUsize>);
+
+    #[tonic::async_trait]
+    impl ShortenUrl for CountingShortener {
+        async fn get_shortened_url(
+            &self,
+            request: Request<Slug>,
+        ) -> Result<Response<ShortenedUrl>, Status> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(Response::new(ShortenedUrl {
+                shortened_url: request.into_inner().slug,
+                ..Default::default()
+            }))
+        }
+
+        async fn create_shortened_url(
+            &self,
+            _: Request<OriginalUrl>,
+        ) -> Result<Response<ShortenedUrl>, Status> {
+            Err(Status::unimplemented("not stubbed"))
+        }
+
+        async fn delete_shortened_url(
+            &self,
+            _: Request<OriginalUrl>,
+        ) -> Result<Response<DeleteResponse>, Status> {
+            Err(Status::unimplemented("not stubbed"))
+        }
+
+        async fn list_shortened_urls(
+            &self,
+            _: Request<ListUrlsRequest>,
+        ) -> Result<Response<ListUrlsResponse>, Status> {
+            Err(Status::unimplemented("not stubbed"))
+        }
+
+        async fn update_shortened_url(
+            &self,
+            _: Request<UpdateUrlRequest>,
+        ) -> Result<Response<ShortenedUrl>, Status> {
+            Err(Status::unimplemented("not stubbed"))
+        }
+
+        async fn delete_shortened_url_by_slug(
+            &self,
+            _: Request<Slug>,
+        ) -> Result<Response<DeleteResponse>, Status> {
+            Err(Status::unimplemented("not stubbed"))
+        }
+    }
+
+    struct Instance {
+        uri: String,
+        calls: Arc<AtomicUsize>,
+        health: HealthReporter,
+    }
+
+    impl Instance {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let uri = format!("http://{}", listener.local_addr().unwrap());
+            let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+            let (mut health, health_service) = tonic_health::server::health_reporter();
+            health.set_service_status("", ServingStatus::Serving).await;
+            let calls = Arc::new(AtomicUsize::new(0));
+            let service = ShortenUrlServer::new(CountingShortener(calls.clone()));
+            tokio::spawn(
+                Server::builder()
+                    .add_service(health_service)
+                    .add_service(service)
+                    .serve_with_incoming(incoming),
+            );
+            Self { uri, calls, health }
+        }
+
+        async fn set_serving(&self, serving: bool) {
+            let status = match serving {
+                true => ServingStatus::Serving,
+                false => ServingStatus::NotServing,
+            };
+            self.health.clone().set_service_status("", status).await;
+        }
+
+        fn take_calls(&self) -> usize {
+            self.calls.swap(0, Ordering::Relaxed)
+        }
+    }
+
+    async fn start_instances(count: usize) -> (Vec<Instance>, Backend) {
+        let mut instances = Vec::new();
+        for _ in 0..count {
+            instances.push(Instance::start().await);
+        }
+        let backend = Backend::connect_lazy(
+            "shortener_service",
+            BackendConfig {
+                endpoints: instances.iter().map(|i| i.uri.clone()).collect(),
+                health_check_interval_ms: 50,
+                ..BackendConfig::default()
+            },
+        )
+        .unwrap();
+        (instances, backend)
+    }
+
+    async fn call(backend: &Backend) -> Result<Response<ShortenedUrl>, Status> {
+        backend
+            .call(Retry::Idempotent, |channel| {
+                let request = backend.request(
+                    Slug {
+                        slug: "abc".to_string(),
+                    },
+                    None,
+                );
+                async move {
+                    ShortenUrlClient::new(channel)
+                        .get_shortened_url(request)
+                        .await
+                }
+            })
+            .await
+    }
+
+    async fn call_many(backend: &Backend, count: usize) {
+        for _ in 0..count {
+            call(backend).await.unwrap();
+        }
+    }
+
+    /// Waits for discovery to report `count` healthy instances and for the balancer to pick
+    /// up the change.
+    async fn wait_for_healthy(backend: &Backend, count: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while backend.healthy_instances() != count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("expected {} healthy instances", count));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test]
+    async fn first_calls_wait_for_discovery() {
+        let (instances, backend) = start_instances(1).await;
+
+        let response = call(&backend).await.unwrap();
+
+        assert_eq!(response.into_inner().shortened_url, "abc");
+        assert_eq!(instances[0].take_calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn spreads_calls_across_instances() {
+        let (instances, backend) = start_instances(3).await;
+        wait_for_healthy(&backend, 3).await;
+
+        call_many(&backend, 90).await;
+
+        for instance in &instances {
+            assert!(instance.take_calls() > 0, "{} got no calls", instance.uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn ejects_and_readmits_instances_by_health() {
+        let (instances, backend) = start_instances(3).await;
+        wait_for_healthy(&backend, 3).await;
+
+        instances[0].set_serving(false).await;
+        wait_for_healthy(&backend, 2).await;
+        for instance in &instances {
+            instance.take_calls();
+        }
+        call_many(&backend, 60).await;
+        assert_eq!(instances[0].take_calls(), 0);
+        assert!(instances[1].take_calls() > 0);
+        assert!(instances[2].take_calls() > 0);
+
+        instances[0].set_serving(true).await;
+        wait_for_healthy(&backend, 3).await;
+        call_many(&backend, 90).await;
+        assert!(instances[0].take_calls() > 0);
+    }
+
+    #[tokio::test]
+    async fn fails_fast_without_healthy_instances() {
+        let (instances, backend) = start_instances(1).await;
+        wait_for_healthy(&backend, 1).await;
+
+        instances[0].set_serving(false).await;
+        wait_for_healthy(&backend, 0).await;
+
+        let status = call(&backend).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(instances[0].take_calls(), 0);
+    }
+}
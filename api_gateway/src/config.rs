@@ -0,0 +1,64 @@
+use crate::auth::AuthConfig;
+use crate::backend::BackendConfig;
+use figment::providers::Serialized;
+use figment::Figment;
+use serde::{Deserialize, Serialize};
+use shared::config::ConfigError;
+use std::time::Duration;
+
+const IDEMPOTENCY_LOCK_MARGIN_SECS: u64 = 2;
+
+/// Gateway-only settings, read from the `[gateway]` section of the shared configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+    pub auth: AuthConfig,
+    pub shortener: BackendConfig,
+    pub analytics: BackendConfig,
+    /// How long responses to requests with an `Idempotency-Key` are replayed.
+    pub idempotency_ttl_secs: u64,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            auth: AuthConfig::default(),
+            shortener: BackendConfig::with_endpoint("http://127.0.0.1:50051/"),
+            analytics: BackendConfig::with_endpoint("http://127.0.0.1:50052/"),
+            idempotency_ttl_secs: 86_400,
+        }
+    }
+}
+
+impl GatewayConfig {
+    pub fn from_figment(figment: &Figment) -> Result<Self, ConfigError> {
+        let config: Self = figment
+            .clone()
+            .join(Serialized::default("gateway", Self::default()))
+            .extract_inner("gateway")?;
+
+        let mut errors = Vec::new();
+        config.auth.validate(&mut errors);
+        config.shortener.validate("shortener", &mut errors);
+        config.analytics.validate("analytics", &mut errors);
+        if config.idempotency_ttl_secs == 0 {
+            errors.push("gateway.idempotency_ttl_secs must be greater than zero".to_string());
+        }
+
+        match errors.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError::Invalid(errors)),
+        }
+    }
+
+    pub fn idempotency_ttl(&self) -> Duration {
+        Duration::from_secs(self.idempotency_ttl_secs)
+    }
+
+    /// How long a create may hold its idempotency key before another request can take it
+    /// over: a little longer than a shortener call can take.
+    pub fn idempotency_lock_ttl(&self) -> Duration {
+        let call = self.shortener.connect_timeout() + self.shortener.request_timeout();
+        Duration::from_secs(call.as_secs_f64().ceil() as u64 + IDEMPOTENCY_LOCK_MARGIN_SECS)
+    }
+}
@@ -0,0 +1,154 @@
+use crate::backend::BackendConfig;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::Code;
+use tonic_discover::discover::Change;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+use tracing::{info, warn};
+
+/// Builds an endpoint with the connection settings shared by every backend instance.
+pub fn endpoint(name: &str, config: &BackendConfig, uri: &str) -> Result<Endpoint> {
+    Ok(Endpoint::from_shared(uri.to_string())
+        .with_context(|| format!("Invalid {} endpoint `{}`", name, uri))?
+        .connect_timeout(config.connect_timeout())
+        .tcp_keepalive(Some(Duration::from_secs(30)))
+        .http2_keep_alive_interval(Duration::from_secs(30))
+        .keep_alive_while_idle(true))
+}
+
+/// Keeps the balanced channel's endpoint set in sync with the configured instances: resolves
+/// the DNS name (if any) every `dns_refresh`, probes every candidate each
+/// `health_check_interval`, and ejects instances that fail the probe. `discovered` is set
+/// once the first round of probes has finished.
+pub async fn run(
+    name: &'static str,
+    config: Arc<BackendConfig>,
+    changes: Sender<Change<String, Endpoint>>,
+    healthy: Arc<AtomicUsize>,
+    discovered: watch::Sender<bool>,
+) {
+    let mut candidates = config.endpoints.clone();
+    // One channel per address, reused by every probe; a lazy channel reconnects by itself.
+    let mut channels: HashMap<String, (Endpoint, Channel)> = HashMap::new();
+    let mut active: HashSet<String> = HashSet::new();
+    let mut last_resolved: Option<Instant> = None;
+    let mut interval = tokio::time::interval(config.health_check_interval());
+
+    loop {
+        interval.tick().await;
+
+        let refresh_due = last_resolved.is_none_or(|at| at.elapsed() >= config.dns_refresh());
+        if let Some(dns) = config.dns.as_ref().filter(|_| refresh_due) {
+            match resolve(dns).await {
+                Ok(resolved) => {
+                    candidates = config.endpoints.iter().cloned().chain(resolved).collect();
+                    last_resolved = Some(Instant::now());
+                }
+                Err(e) => warn!("Failed to resolve {} `{}`: {:?}", name, dns, e),
+            }
+        }
+
+        channels.retain(|uri, _| candidates.contains(uri));
+        for uri in &candidates {
+            if channels.contains_key(uri) {
+                continue;
+            }
+            match endpoint(name, &config, uri) {
+                Ok(endpoint) => {
+                    let channel = endpoint.connect_lazy();
+                    channels.insert(uri.clone(), (endpoint, channel));
+                }
+                Err(e) => warn!("{:?}", e),
+            }
+        }
+
+        let mut probes = JoinSet::new();
+        for (uri, (endpoint, channel)) in &channels {
+            let (uri, endpoint, channel) = (uri.clone(), endpoint.clone(), channel.clone());
+            let timeout = config.request_timeout();
+            probes.spawn(async move {
+                let healthy = tokio::time::timeout(timeout, probe(channel))
+                    .await
+                    .unwrap_or(false);
+                (uri, endpoint, healthy)
+            });
+        }
+
+        let mut seen = HashSet::new();
+        while let Some(Ok((uri, endpoint, is_healthy))) = probes.join_next().await {
+            seen.insert(uri.clone());
+            let change = match (is_healthy, active.contains(&uri)) {
+                (true, false) => {
+                    info!("Adding {} instance `{}`", name, uri);
+                    active.insert(uri.clone());
+                    Change::Insert(uri, endpoint)
+                }
+                (false, true) => {
+                    warn!("Ejecting unhealthy {} instance `{}`", name, uri);
+                    active.remove(&uri);
+                    Change::Remove(uri)
+                }
+                _ => continue,
+            };
+            if changes.send(change).await.is_err() {
+                return;
+            }
+        }
+
+        // Instances that disappeared from DNS are removed as well.
+        for uri in active
+            .iter()
+            .filter(|uri| !seen.contains(*uri))
+            .cloned()
+            .collect::<Vec<_>>()
+        {
+            info!("Removing {} instance `{}`", name, uri);
+            active.remove(&uri);
+            if changes.send(Change::Remove(uri)).await.is_err() {
+                return;
+            }
+        }
+
+        healthy.store(active.len(), Ordering::Relaxed);
+        discovered.send_replace(true);
+    }
+}
+
+/// An instance is healthy when `grpc.health.v1.Health` reports it as serving; instances that do
+/// not implement the health service only need to accept connections.
+async fn probe(channel: Channel) -> bool {
+    let request = HealthCheckRequest {
+        service: String::new(),
+    };
+    match HealthClient::new(channel).check(request).await {
+        Ok(response) => response.into_inner().status == ServingStatus::Serving as i32,
+        Err(status) => status.code() == Code::Unimplemented,
+    }
+}
+
+async fn resolve(dns: &str) -> Result<Vec<String>> {
+    let uri: Uri = dns
+        .parse()
+        .with_context(|| format!("Invalid URI `{}`", dns))?;
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow!("Missing host in `{}`", dns))?;
+    let scheme = uri.scheme_str().unwrap_or("http");
+    let port = uri
+        .port_u16()
+        .unwrap_or(if scheme == "https" { 443 } else { 80 });
+
+    Ok(tokio::net::lookup_host((host, port))
+        .await?
+        .map(|addr| format!("{}://{}", scheme, addr))
+        .collect())
+}
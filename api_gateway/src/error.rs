@@ -0,0 +1,324 @@
+use crate::auth::AuthError;
+use axum::extract::Request;
+use axum::http::header::{CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use thiserror::Error;
+use tonic::Code;
+use tracing::error;
+use utoipa::ToSchema;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("gRPC request failed: {0}")]
+    GrpcError(#[from] tonic::Status),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(#[from] AuthError),
+
+    #[error("Rate limit exceeded")]
+    RateLimited,
+
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Idempotency key reused with a different request")]
+    IdempotencyKeyReused,
+
+    #[error("A request with this idempotency key is still in progress")]
+    IdempotencyInProgress,
+
+    #[error("Internal server error: {0}")]
+    InternalServerError(String),
+}
+
+/// RFC 7807 problem details. `code` is a stable, machine-readable error identifier.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &'static str, detail: Option<String>) -> Self {
+        Self {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            code,
+            request_id: None,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let body = serde_json::to_vec(&self).unwrap_or_default();
+        let mut response = (self.status(), [(CONTENT_TYPE, PROBLEM_JSON)], body).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl ApiError {
+    pub fn problem(&self) -> Problem {
+        match self {
+            ApiError::GrpcError(status) => {
+                let (http_status, code) = match status.code() {
+                    Code::NotFound => (StatusCode::NOT_FOUND, "not_found"),
+                    Code::AlreadyExists => (StatusCode::CONFLICT, "already_exists"),
+                    Code::InvalidArgument | Code::OutOfRange | Code::FailedPrecondition => {
+                        (StatusCode::BAD_REQUEST, "invalid_argument")
+                    }
+                    Code::PermissionDenied => (StatusCode::FORBIDDEN, "permission_denied"),
+                    Code::Unauthenticated => (StatusCode::UNAUTHORIZED, "unauthenticated"),
+                    Code::ResourceExhausted => {
+                        (StatusCode::TOO_MANY_REQUESTS, "resource_exhausted")
+                    }
+                    Code::Unavailable => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+                    Code::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, "deadline_exceeded"),
+                    _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+                };
+                // Backend messages for server-side failures may leak internals.
+                let detail = (!http_status.is_server_error()).then(|| status.message().to_string());
+                Problem::new(http_status, code, detail)
+            }
+            ApiError::Unauthorized(err) => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthenticated",
+                Some(err.to_string()),
+            ),
+            ApiError::RateLimited => Problem::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                Some("Too many requests, retry later".to_string()),
+            ),
+            ApiError::BadRequest(msg) => {
+                Problem::new(StatusCode::BAD_REQUEST, "bad_request", Some(msg.clone()))
+            }
+            ApiError::IdempotencyKeyReused => Problem::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "idempotency_key_reused",
+                Some(self.to_string()),
+            ),
+            ApiError::IdempotencyInProgress => Problem::new(
+                StatusCode::CONFLICT,
+                "idempotency_in_progress",
+                Some(self.to_string()),
+            ),
+            ApiError::InternalServerError(_) => {
+                Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", None)
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let problem = self.problem();
+        if problem.status().is_server_error() {
+            error!("Request failed: {}", self);
+        }
+
+        let mut response = problem.into_response();
+        if let ApiError::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// Fills in the request id of problem responses produced further down the stack.
+pub async fn problem_details(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(request).await;
+    match (response.extensions().get::<Problem>(), request_id) {
+        (Some(problem), Some(request_id)) => {
+            let problem = Problem {
+                request_id: Some(request_id),
+                ..problem.clone()
+            };
+            let (mut parts, _) = response.into_parts();
+            let body = serde_json::to_vec(&problem).unwrap_or_default();
+            parts.extensions.insert(problem);
+            Response::from_parts(parts, body.into())
+        }
+        _ => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::middleware::from_fn;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    #[test]
+    fn maps_grpc_codes_to_http_statuses() {
+        let cases = [
+            (Code::NotFound, StatusCode::NOT_FOUND, "not_found"),
+            (Code::AlreadyExists, StatusCode::CONFLICT, "already_exists"),
+            (
+                Code::InvalidArgument,
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                Code::OutOfRange,
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                Code::FailedPrecondition,
+                StatusCode::BAD_REQUEST,
+                "invalid_argument",
+            ),
+            (
+                Code::PermissionDenied,
+                StatusCode::FORBIDDEN,
+                "permission_denied",
+            ),
+            (
+                Code::Unauthenticated,
+                StatusCode::UNAUTHORIZED,
+                "unauthenticated",
+            ),
+            (
+                Code::ResourceExhausted,
+                StatusCode::TOO_MANY_REQUESTS,
+                "resource_exhausted",
+            ),
+            (
+                Code::Unavailable,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+            ),
+            (
+                Code::DeadlineExceeded,
+                StatusCode::GATEWAY_TIMEOUT,
+                "deadline_exceeded",
+            ),
+            (
+                Code::Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+            (Code::Unknown, StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+            (
+                Code::DataLoss,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+            (
+                Code::Unimplemented,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+        ];
+        for (code, status, problem_code) in cases {
+            let problem = ApiError::from(tonic::Status::new(code, "backend detail")).problem();
+            assert_eq!(problem.status, status.as_u16(), "{:?}", code);
+            assert_eq!(problem.code, problem_code, "{:?}", code);
+            // Server-side failures do not pass on the backend's message.
+            let detail = (!status.is_server_error()).then(|| "backend detail".to_string());
+            assert_eq!(problem.detail, detail, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn maps_gateway_errors() {
+        let cases = [
+            (
+                ApiError::RateLimited,
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+            ),
+            (
+                ApiError::BadRequest("no url".into()),
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+            ),
+            (
+                ApiError::IdempotencyKeyReused,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "idempotency_key_reused",
+            ),
+            (
+                ApiError::IdempotencyInProgress,
+                StatusCode::CONFLICT,
+                "idempotency_in_progress",
+            ),
+            (
+                ApiError::InternalServerError("secret".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+        ];
+        for (error, status, code) in cases {
+            let problem = error.problem();
+            assert_eq!(problem.status, status.as_u16(), "{}", error);
+            assert_eq!(problem.code, code, "{}", error);
+        }
+        assert_eq!(
+            ApiError::InternalServerError("secret".into())
+                .problem()
+                .detail,
+            None
+        );
+    }
+
+    async fn problem_body(request_id: Option<&str>) -> serde_json::Value {
+        let app = Router::new()
+            .route("/", get(|| async { ApiError::BadRequest("no url".into()) }))
+            .layer(from_fn(problem_details));
+        let mut request = Request::get("/");
+        if let Some(request_id) = request_id {
+            request = request.header("x-request-id", request_id);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()[CONTENT_TYPE], PROBLEM_JSON);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn problem_details_fills_in_the_request_id() {
+        let problem = problem_body(Some("req-1")).await;
+        assert_eq!(problem["request_id"], "req-1");
+        assert_eq!(problem["code"], "bad_request");
+        assert_eq!(problem["detail"], "no url");
+
+        let problem = problem_body(None).await;
+        assert!(problem.get("request_id").is_none());
+    }
+}
@@ -0,0 +1,274 @@
+use crate::auth::Identity;
+use crate::backend::{Backend, Retry};
+use crate::echourl::analytics_client::AnalyticsClient;
+use crate::echourl::shorten_url_client::ShortenUrlClient;
+use crate::echourl::{
+    LinkStatsRequest, ListUrlsRequest, OriginalUrl, ShortenedUrl, Slug, UpdateUrlRequest,
+};
+use crate::error::ApiError;
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{
+    ComplexObject, Context, EmptySubscription, ErrorExtensions, Object, Schema, SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::response::{Html, IntoResponse};
+use axum::routing::get;
+use axum::{Extension, Router};
+use shared::validation::validate_url;
+use tonic::Code;
+
+const MAX_DEPTH: usize = 8;
+const MAX_COMPLEXITY: usize = 500;
+const DEFAULT_PAGE_SIZE: u32 = 20;
+
+pub type EchoSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+struct Shortener(Backend);
+struct Analytics(Backend);
+
+pub fn schema(shortener: Backend, analytics: Backend) -> EchoSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(Shortener(shortener))
+        .data(Analytics(analytics))
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// `POST /graphql` executes queries, `GET /graphql` serves GraphiQL.
+pub fn router(schema: EchoSchema) -> Router {
+    Router::new()
+        .route("/graphql", get(graphiql).post(graphql_handler))
+        .layer(Extension(schema))
+}
+
+async fn graphql_handler(
+    Extension(schema): Extension<EchoSchema>,
+    identity: Option<Extension<Identity>>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let mut request = request.into_inner();
+    if let Some(Extension(identity)) = identity {
+        request = request.data(identity);
+    }
+    schema.execute(request).await.into()
+}
+
+async fn graphiql() -> impl IntoResponse {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+fn shortener<'a>(ctx: &Context<'a>) -> &'a Backend {
+    &ctx.data_unchecked::<Shortener>().0
+}
+
+/// Surfaces backend failures with the same error codes as the REST API.
+fn graphql_error(error: impl Into<ApiError>) -> async_graphql::Error {
+    let problem = error.into().problem();
+    async_graphql::Error::new(problem.detail.unwrap_or(problem.title))
+        .extend_with(|_, e| e.set("code", problem.code))
+}
+
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Link {
+    id: i32,
+    original_url: String,
+    slug: String,
+    clicks: i32,
+    created_at: String,
+}
+
+impl From<ShortenedUrl> for Link {
+    fn from(url: ShortenedUrl) -> Self {
+        Self {
+            id: url.id,
+            original_url: url.original_url,
+            slug: url.shortened_url,
+            clicks: url.clicks,
+            created_at: url.created_at,
+        }
+    }
+}
+
+#[ComplexObject]
+impl Link {
+    /// Click statistics from analytics_service, with daily counts for the trailing `days`.
+    #[graphql(complexity = 10)]
+    async fn stats(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 30)] days: u32,
+    ) -> async_graphql::Result<LinkStats> {
+        let identity = ctx.data_opt::<Identity>();
+        let message = LinkStatsRequest {
+            slug: self.slug.clone(),
+            days,
+        };
+        let backend = &ctx.data_unchecked::<Analytics>().0;
+        let stats = backend
+            .call(Retry::Idempotent, |channel| {
+                let request = backend.request(message.clone(), identity);
+                async move { AnalyticsClient::new(channel).get_link_stats(request).await }
+            })
+            .await
+            .map_err(graphql_error)?
+            .into_inner();
+
+        Ok(LinkStats {
+            total_clicks: stats.total_clicks,
+            daily: stats
+                .daily
+                .into_iter()
+                .map(|day| DailyClicks {
+                    date: day.date,
+                    clicks: day.clicks,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct LinkStats {
+    total_clicks: i32,
+    daily: Vec<DailyClicks>,
+}
+
+#[derive(SimpleObject)]
+pub struct DailyClicks {
+    date: String,
+    clicks: i32,
+}
+
+#[derive(SimpleObject)]
+pub struct LinkPage {
+    nodes: Vec<Link>,
+    /// Pass as `after` to fetch the next page; null on the last page.
+    next_page_token: Option<String>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Links in creation order, `first` at a time.
+    #[graphql(complexity = "first.unwrap_or(DEFAULT_PAGE_SIZE) as usize * child_complexity")]
+    async fn links(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<u32>,
+        after: Option<String>,
+    ) -> async_graphql::Result<LinkPage> {
+        let identity = ctx.data_opt::<Identity>();
+        let backend = shortener(ctx);
+        let message = ListUrlsRequest {
+            page_size: first.unwrap_or(DEFAULT_PAGE_SIZE),
+            page_token: after.unwrap_or_default(),
+        };
+        let page = backend
+            .call(Retry::Idempotent, |channel| {
+                let request = backend.request(message.clone(), identity);
+                async move {
+                    ShortenUrlClient::new(channel)
+                        .list_shortened_urls(request)
+                        .await
+                }
+            })
+            .await
+            .map_err(graphql_error)?
+            .into_inner();
+
+        Ok(LinkPage {
+            nodes: page.urls.into_iter().map(Link::from).collect(),
+            next_page_token: Some(page.next_page_token).filter(|token| !token.is_empty()),
+        })
+    }
+
+    async fn link(&self, ctx: &Context<'_>, slug: String) -> async_graphql::Result<Option<Link>> {
+        let identity = ctx.data_opt::<Identity>();
+        let backend = shortener(ctx);
+        match backend
+            .call(Retry::Idempotent, |channel| {
+                let request = backend.request(Slug { slug: slug.clone() }, identity);
+                async move {
+                    ShortenUrlClient::new(channel)
+                        .get_shortened_url(request)
+                        .await
+                }
+            })
+            .await
+        {
+            Ok(response) => Ok(Some(response.into_inner().into())),
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(graphql_error(status)),
+        }
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_link(&self, ctx: &Context<'_>, url: String) -> async_graphql::Result<Link> {
+        validate_url(&url).map_err(|e| graphql_error(ApiError::BadRequest(e)))?;
+        let identity = ctx.data_opt::<Identity>();
+        let backend = shortener(ctx);
+        let response = backend
+            .call(Retry::Never, |channel| {
+                let request = backend.request(OriginalUrl { url: url.clone() }, identity);
+                async move {
+                    ShortenUrlClient::new(channel)
+                        .create_shortened_url(request)
+                        .await
+                }
+            })
+            .await
+            .map_err(graphql_error)?;
+
+        Ok(response.into_inner().into())
+    }
+
+    async fn update_link(
+        &self,
+        ctx: &Context<'_>,
+        slug: String,
+        url: String,
+    ) -> async_graphql::Result<Link> {
+        validate_url(&url).map_err(|e| graphql_error(ApiError::BadRequest(e)))?;
+        let identity = ctx.data_opt::<Identity>();
+        let backend = shortener(ctx);
+        let message = UpdateUrlRequest { slug, url };
+        let response = backend
+            .call(Retry::Idempotent, |channel| {
+                let request = backend.request(message.clone(), identity);
+                async move {
+                    ShortenUrlClient::new(channel)
+                        .update_shortened_url(request)
+                        .await
+                }
+            })
+            .await
+            .map_err(graphql_error)?;
+
+        Ok(response.into_inner().into())
+    }
+
+    async fn delete_link(&self, ctx: &Context<'_>, slug: String) -> async_graphql::Result<bool> {
+        let identity = ctx.data_opt::<Identity>();
+        let backend = shortener(ctx);
+        let response = backend
+            .call(Retry::Never, |channel| {
+                let request = backend.request(Slug { slug: slug.clone() }, identity);
+                async move {
+                    ShortenUrlClient::new(channel)
+                        .delete_shortened_url_by_slug(request)
+                        .await
+                }
+            })
+            .await
+            .map_err(graphql_error)?;
+
+        Ok(response.into_inner().success)
+    }
+}
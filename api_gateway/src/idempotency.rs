@@ -0,0 +1,513 @@
+use crate::auth::Identity;
+use crate::error::{ApiError, PROBLEM_JSON};
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::header::{CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use metrics::counter;
+use redis::{AsyncCommands, RedisResult, Script};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::connection::RedisPool;
+use shared::trace_context::REQUEST_ID;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const MAX_KEY_LENGTH: usize = 255;
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+const CLAIM_ATTEMPTS: usize = 3;
+
+/// Deletes a pending record only while it is still the one this request wrote.
+static RELEASE_PENDING: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0
+        "#,
+    )
+});
+
+/// Where records are kept. Tests use an in-memory map in place of Redis.
+#[derive(Clone)]
+enum Storage {
+    Redis(RedisPool),
+    #[cfg(test)]
+    Memory(Arc<std::sync::Mutex<std::collections::HashMap<String, String>>>),
+}
+
+impl Storage {
+    /// `SET NX EX`: whether `value` was written because `key` was free.
+    async fn insert(&self, key: &str, value: &str, ttl: Duration) -> RedisResult<bool> {
+        match self {
+            Self::Redis(redis) => redis::cmd("SET")
+                .arg(key)
+                .arg(value)
+                .arg("NX")
+                .arg("EX")
+                .arg(ttl.as_secs())
+                .query_async::<Option<String>>(&mut redis.connection()?)
+                .await
+                .map(|set| set.is_some()),
+            #[cfg(test)]
+            Self::Memory(records) => {
+                let mut records = records.lock().unwrap();
+                if records.contains_key(key) {
+                    return Ok(false);
+                }
+                records.insert(key.to_string(), value.to_string());
+                Ok(true)
+            }
+        }
+    }
+
+    async fn get(&self, key: &str) -> RedisResult<Option<String>> {
+        match self {
+            Self::Redis(redis) => redis.connection()?.get(key).await,
+            #[cfg(test)]
+            Self::Memory(records) => Ok(records.lock().unwrap().get(key).cloned()),
+        }
+    }
+
+    async fn set(&self, key: &str, value: String, ttl: Duration) -> RedisResult<()> {
+        match self {
+            Self::Redis(redis) => redis.connection()?.set_ex(key, value, ttl.as_secs()).await,
+            #[cfg(test)]
+            Self::Memory(records) => {
+                records.lock().unwrap().insert(key.to_string(), value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Deletes `key` only while it still holds `pending`.
+    async fn release(&self, key: &str, pending: String) -> RedisResult<()> {
+        match self {
+            Self::Redis(redis) => RELEASE_PENDING
+                .key(key)
+                .arg(pending)
+                .invoke_async::<i64>(&mut redis.connection()?)
+                .await
+                .map(drop),
+            #[cfg(test)]
+            Self::Memory(records) => {
+                let mut records = records.lock().unwrap();
+                if records.get(key) == Some(&pending) {
+                    records.remove(key);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct IdempotencyStore {
+    storage: Storage,
+    ttl: Duration,
+    lock_ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum Record {
+    Pending {
+        fingerprint: String,
+        /// Distinguishes this request's marker from one written after it expired.
+        #[serde(default)]
+        token: String,
+    },
+    Complete {
+        fingerprint: String,
+        status: u16,
+        content_type: Option<String>,
+        location: Option<String>,
+        body: String,
+    },
+}
+
+impl Record {
+    fn fingerprint(&self) -> &str {
+        match self {
+            Record::Pending { fingerprint, .. } | Record::Complete { fingerprint, .. } => {
+                fingerprint
+            }
+        }
+    }
+}
+
+/// Outcome of claiming a key for a request.
+enum Claim {
+    Acquired,
+    /// Another request holds the key. `None` when its record could not be read.
+    Taken(Option<Record>),
+}
+
+impl IdempotencyStore {
+    /// Responses are replayed for `ttl`; a request still in progress holds its key for at
+    /// most `lock_ttl`.
+    pub fn new(redis: RedisPool, ttl: Duration, lock_ttl: Duration) -> Self {
+        Self {
+            storage: Storage::Redis(redis),
+            ttl,
+            lock_ttl,
+        }
+    }
+
+    /// Writes `pending` under `key` unless a record is there. The record can expire or be
+    /// released between the write and the read that follows it, leaving the key free to
+    /// claim again.
+    async fn claim(&self, key: &str, pending: &str) -> RedisResult<Claim> {
+        for _ in 0..CLAIM_ATTEMPTS {
+            if self.storage.insert(key, pending, self.lock_ttl).await? {
+                return Ok(Claim::Acquired);
+            }
+            if let Some(stored) = self.storage.get(key).await? {
+                return Ok(Claim::Taken(serde_json::from_str(&stored).ok()));
+            }
+        }
+        Ok(Claim::Taken(None))
+    }
+}
+
+/// Replays the stored response for a repeated `Idempotency-Key`, so retried creates do not
+/// produce duplicate links. Keys are scoped to the caller and bound to the request body.
+pub async fn idempotency(
+    Extension(store): Extension<Arc<IdempotencyStore>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(next.run(request).await);
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LENGTH)
+        .ok_or_else(|| ApiError::BadRequest("Invalid Idempotency-Key header".into()))?
+        .to_string();
+
+    let scope = request
+        .extensions()
+        .get::<Identity>()
+        .map(|identity| identity.user_id.clone())
+        .unwrap_or_else(|| "anonymous".to_string());
+    let redis_key = format!("idempotency:{}:{}", scope, key);
+
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|_| ApiError::BadRequest("Request body too large".into()))?;
+    let fingerprint = format!(
+        "{:x}",
+        Sha256::new()
+            .chain_update(parts.method.as_str())
+            .chain_update(parts.uri.path())
+            .chain_update(&body)
+            .finalize()
+    );
+
+    let pending = serde_json::to_string(&Record::Pending {
+        fingerprint: fingerprint.clone(),
+        token: format!("{:016x}", rand::random::<u64>()),
+    })
+    .unwrap_or_default();
+    // Redis only makes retries safe; without it requests go through unprotected rather than
+    // fail.
+    match store.claim(&redis_key, &pending).await {
+        Ok(Claim::Acquired) => {}
+        Ok(Claim::Taken(record)) => {
+            return Ok(replay(record, &fingerprint, &key, request_id.as_deref()));
+        }
+        Err(e) => return Ok(bypass(e, Request::from_parts(parts, Body::from(body)), next).await),
+    }
+
+    // Released on every path that does not store a response, including the client going
+    // away mid-request.
+    let mut lock = PendingLock {
+        storage: store.storage.clone(),
+        key: redis_key.clone(),
+        pending: Some(pending),
+    };
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    // Server errors are not remembered so that the client can retry them.
+    if response.status().is_server_error() {
+        lock.release().await;
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+    let header = |name| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let record = Record::Complete {
+        fingerprint,
+        status: parts.status.as_u16(),
+        content_type: header(CONTENT_TYPE),
+        location: header(LOCATION),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    };
+    match store
+        .storage
+        .set(
+            &redis_key,
+            serde_json::to_string(&record).unwrap_or_default(),
+            store.ttl,
+        )
+        .await
+    {
+        Ok(()) => lock.pending = None,
+        Err(e) => error!("Failed to store idempotent response `{}`: {:?}", key, e),
+    }
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Answers a request whose key another request already holds.
+fn replay(
+    record: Option<Record>,
+    fingerprint: &str,
+    key: &str,
+    request_id: Option<&str>,
+) -> Response {
+    let Some(record) = record else {
+        return ApiError::IdempotencyInProgress.into_response();
+    };
+    if record.fingerprint() != fingerprint {
+        return ApiError::IdempotencyKeyReused.into_response();
+    }
+    match record {
+        Record::Pending { .. } => ApiError::IdempotencyInProgress.into_response(),
+        Record::Complete {
+            status,
+            content_type,
+            location,
+            body,
+            ..
+        } => {
+            info!("Replaying response for idempotency key `{}`", key);
+            let body = if content_type.as_deref() == Some(PROBLEM_JSON) {
+                with_request_id(body, request_id)
+            } else {
+                body
+            };
+            let mut response =
+                (StatusCode::from_u16(status).unwrap_or(StatusCode::OK), body).into_response();
+            let headers = response.headers_mut();
+            for (name, value) in [(CONTENT_TYPE, content_type), (LOCATION, location)] {
+                if let Some(Ok(value)) = value.as_deref().map(HeaderValue::from_str) {
+                    headers.insert(name, value);
+                }
+            }
+            headers.insert("idempotent-replayed", HeaderValue::from_static("true"));
+            response
+        }
+    }
+}
+
+/// A stored problem names the request that first produced it; the replay names the retry.
+fn with_request_id(body: String, request_id: Option<&str>) -> String {
+    let Ok(mut problem) = serde_json::from_str::<serde_json::Map<_, _>>(&body) else {
+        return body;
+    };
+    match request_id {
+        Some(request_id) => problem.insert("request_id".to_string(), request_id.into()),
+        None => problem.remove("request_id"),
+    };
+    serde_json::to_string(&problem).unwrap_or(body)
+}
+
+/// The pending record of a request in progress, deleted on drop unless a response replaced it.
+struct PendingLock {
+    storage: Storage,
+    key: String,
+    pending: Option<String>,
+}
+
+impl PendingLock {
+    async fn release(mut self) {
+        if let Some(pending) = self.pending.take() {
+            release_pending(&self.storage, &self.key, pending).await;
+        }
+    }
+}
+
+impl Drop for PendingLock {
+    fn drop(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let storage = self.storage.clone();
+        let key = std::mem::take(&mut self.key);
+        tokio::spawn(async move { release_pending(&storage, &key, pending).await });
+    }
+}
+
+async fn release_pending(storage: &Storage, key: &str, pending: String) {
+    if let Err(e) = storage.release(key, pending).await {
+        error!("Failed to release idempotency key `{}`: {:?}", key, e);
+    }
+}
+
+async fn bypass(e: redis::RedisError, request: Request, next: Next) -> Response {
+    warn!(
+        "Idempotency store unavailable, handling the request without it: {:?}",
+        e
+    );
+    counter!("idempotency_bypassed_total").increment(1);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::problem_details;
+    use axum::middleware::from_fn;
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Semaphore;
+    use tower::ServiceExt;
+
+    /// A create endpoint that counts its calls, each taking a permit from `gate` for good.
+    struct Endpoint {
+        calls: AtomicUsize,
+        gate: Semaphore,
+        status: StatusCode,
+    }
+
+    fn app(status: StatusCode, permits: usize) -> (Router, Arc<Endpoint>) {
+        let endpoint = Arc::new(Endpoint {
+            calls: AtomicUsize::new(0),
+            gate: Semaphore::new(permits),
+            status,
+        });
+        let store = IdempotencyStore {
+            storage: Storage::Memory(Default::default()),
+            ttl: Duration::from_secs(60),
+            lock_ttl: Duration::from_secs(10),
+        };
+        let handler = {
+            let endpoint = endpoint.clone();
+            move |body: String| async move {
+                endpoint.calls.fetch_add(1, Ordering::SeqCst);
+                endpoint.gate.acquire().await.unwrap().forget();
+                if endpoint.status.is_client_error() {
+                    return ApiError::BadRequest(body).into_response();
+                }
+                (endpoint.status, [(LOCATION, "/abc")], body).into_response()
+            }
+        };
+        let router = Router::new()
+            .route("/createurl", post(handler).layer(from_fn(idempotency)))
+            .layer(Extension(Arc::new(store)))
+            .layer(from_fn(problem_details));
+        (router, endpoint)
+    }
+
+    fn create(body: &str, request_id: &str) -> Request {
+        Request::post("/createurl")
+            .header(IDEMPOTENCY_KEY, "key")
+            .header(REQUEST_ID, request_id)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> String {
+        let bytes = to_bytes(response.into_body(), MAX_BODY_BYTES)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_the_first_response() {
+        let (app, endpoint) = app(StatusCode::CREATED, 1);
+
+        let first = app.clone().oneshot(create("a", "r1")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(first.headers().get("idempotent-replayed").is_none());
+        assert_eq!(body(first).await, "a");
+
+        let replayed = app.oneshot(create("a", "r2")).await.unwrap();
+        assert_eq!(replayed.status(), StatusCode::CREATED);
+        assert_eq!(replayed.headers()["idempotent-replayed"], "true");
+        assert_eq!(replayed.headers()[LOCATION], "/abc");
+        assert_eq!(body(replayed).await, "a");
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_key_reused_with_another_body() {
+        let (app, endpoint) = app(StatusCode::CREATED, 1);
+
+        app.clone().oneshot(create("a", "r1")).await.unwrap();
+        let reused = app.oneshot(create("b", "r2")).await.unwrap();
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_retry_while_the_first_is_in_flight() {
+        let (app, endpoint) = app(StatusCode::CREATED, 0);
+
+        let first = tokio::spawn(app.clone().oneshot(create("a", "r1")));
+        while endpoint.calls.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        let retry = app.clone().oneshot(create("a", "r2")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+
+        endpoint.gate.add_permits(1);
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::CREATED);
+        let replayed = app.oneshot(create("a", "r3")).await.unwrap();
+        assert_eq!(replayed.headers()["idempotent-replayed"], "true");
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_not_remembered() {
+        let (app, endpoint) = app(StatusCode::SERVICE_UNAVAILABLE, 2);
+
+        app.clone().oneshot(create("a", "r1")).await.unwrap();
+        let retry = app.oneshot(create("a", "r2")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(retry.headers().get("idempotent-replayed").is_none());
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn replayed_problems_carry_the_current_request_id() {
+        let (app, _) = app(StatusCode::BAD_REQUEST, 1);
+
+        let first = app.clone().oneshot(create("a", "r1")).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_str(&body(first).await).unwrap();
+        assert_eq!(problem["request_id"], "r1");
+
+        let replayed = app.oneshot(create("a", "r2")).await.unwrap();
+        assert_eq!(replayed.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(replayed.headers()[CONTENT_TYPE], PROBLEM_JSON);
+        let problem: serde_json::Value = serde_json::from_str(&body(replayed).await).unwrap();
+        assert_eq!(problem["request_id"], "r2");
+        assert_eq!(problem["code"], "bad_request");
+    }
+}
@@ -1,88 +1,185 @@
+use crate::auth::{authenticate, Identity, JwtVerifier};
+use crate::backend::{Backend, Retry};
+use crate::config::GatewayConfig;
 use crate::echourl::shorten_url_client::ShortenUrlClient;
 use crate::echourl::{DeleteResponse, OriginalUrl, ShortenedUrl};
-use anyhow::{Context, Result};
+use crate::error::{problem_details, ApiError};
+use crate::extract::Json;
+use crate::idempotency::{idempotency, IdempotencyStore};
+use anyhow::{anyhow, Context, Result};
+use axum::handler::Handler;
 use axum::http::StatusCode;
+use axum::middleware::{from_fn, from_fn_with_state};
+use axum::response::IntoResponse;
 use axum::routing::{delete, post};
-use axum::{Extension, Json, Router};
+use axum::{Extension, Router};
 use serde::{Deserialize, Serialize};
-use std::env;
-use thiserror::Error;
-use tonic::transport::Channel;
-use tonic::Request;
+use shared::config::{load_figment, Config, ServiceDefaults};
+use shared::connection::connect_redis;
+use shared::health::{self, Readiness};
+use shared::identity::IdentitySigner;
+use shared::prometheus;
+use shared::rate_limit::{rate_limit, RateLimiter};
+use shared::shutdown::Shutdown;
+use shared::telemetry;
+use shared::trace_context::{self, propagate};
+use shared::validation::validate_url;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tower::ServiceBuilder;
 use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
-use tracing::{error, info, Level};
+use tracing::{info, warn};
+
+mod auth;
+mod backend;
+mod config;
+mod discovery;
+mod error;
+mod extract;
+mod graphql;
+mod idempotency;
+mod v1;
 
 mod echourl {
     tonic::include_proto!("echourl");
 }
 
-#[derive(Debug, Error)]
-pub enum ApiError {
-    #[error("gRPC request failed: {0}")]
-    GrpcError(#[from] tonic::Status),
-
-    #[error("Internal server error: {0}")]
-    InternalServerError(String),
-}
-
-impl From<ApiError> for StatusCode {
-    fn from(err: ApiError) -> Self {
-        match err {
-            ApiError::GrpcError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            ApiError::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        }
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
-    unsafe {
-        env::set_var("RUST_LOG", "info");
+    let figment = load_figment(&ServiceDefaults {
+        name: "api_gateway",
+        listen_addr: "0.0.0.0:3000",
+        metrics_addr: "0.0.0.0:9100",
+    })?;
+    let config = Config::from_figment(&figment)?;
+    let gateway = GatewayConfig::from_figment(&figment)?;
+
+    let _telemetry =
+        telemetry::init(&config.telemetry).context("Failed to initialise telemetry")?;
+    let metrics = prometheus::install()?;
+    let shutdown = Shutdown::install(config.server.shutdown_grace_period());
+
+    let shortener = Backend::connect_lazy("shortener_service", gateway.shortener.clone())?;
+    let analytics = Backend::connect_lazy("analytics_service", gateway.analytics.clone())?;
+
+    let signer = IdentitySigner::new(&config.identity);
+    let verifier = Arc::new(
+        JwtVerifier::new(&gateway.auth, signer.clone())
+            .context("Failed to configure JWT verifier")?,
+    );
+    if verifier.is_enabled() && !signer.is_enabled() {
+        warn!("identity.signing_secret is not set, so backends will not see caller identities");
     }
 
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
-
-    let grpc_channel = Channel::from_static("http://127.0.0.1:50051/")
-        .connect()
+    let redis = connect_redis(&config.redis)
         .await
-        .context("Failed to connect to gRPC server")?;
+        .context("Redis connection failed")?;
+    let limiter = Arc::new(
+        RateLimiter::new(
+            "api_gateway",
+            config.rate_limit.clone(),
+            Some(redis.clone()),
+        )
+        .with_rejection(|| ApiError::RateLimited.into_response()),
+    );
+    let idempotency_store = Arc::new(IdempotencyStore::new(
+        redis.clone(),
+        gateway.idempotency_ttl(),
+        gateway.idempotency_lock_ttl(),
+    ));
+
+    let readiness = Readiness::new()
+        .draining(&shutdown)
+        .redis_cache(redis.clone())
+        .check("shortener_service", {
+            let shortener = shortener.clone();
+            move || {
+                let healthy = shortener.healthy_instances();
+                async move {
+                    match healthy {
+                        0 => Err(anyhow!("no healthy instances")),
+                        _ => Ok(()),
+                    }
+                }
+            }
+        });
 
     let app = Router::new()
-        .route("/createurl", post(create_url))
+        .route("/createurl", post(create_url.layer(from_fn(idempotency))))
         .route("/deleteurl", delete(delete_url))
+        .merge(v1::router())
+        .merge(graphql::router(graphql::schema(
+            shortener.clone(),
+            analytics,
+        )))
+        .route_layer(from_fn_with_state(limiter, rate_limit))
         .layer(
             ServiceBuilder::new()
-                .layer(Extension(grpc_channel))
+                .layer(Extension(shortener))
+                .layer(Extension(idempotency_store))
+                .layer(from_fn(propagate))
                 .layer(CompressionLayer::new())
-                .layer(TraceLayer::new_for_http()),
-        );
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .context("Failed to bind HTTP server to port 3000")?;
-    info!("🚀 HTTP server listening on 0.0.0.0:3000");
-
-    axum::serve(listener, app)
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(trace_context::http_span)
+                        .on_response(telemetry::on_http_response),
+                )
+                .layer(config.cors.layer())
+                .layer(from_fn(problem_details))
+                .layer(from_fn_with_state(verifier, authenticate)),
+        )
+        .merge(health::router(readiness));
+
+    let addr = config.server.listen_addr;
+    let listener = tokio::net::TcpListener::bind(addr)
         .await
-        .context("HTTP server error")?;
+        .with_context(|| format!("Failed to bind HTTP server to {}", addr))?;
+    info!("🚀 HTTP server listening on {}", addr);
+
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.requested());
+    tokio::try_join!(
+        shutdown.drain("HTTP server", async {
+            server.await.context("HTTP server error")
+        }),
+        shutdown.drain(
+            "Metrics server",
+            prometheus::serve(
+                config.server.metrics_addr,
+                prometheus::router(metrics),
+                &shutdown
+            )
+        ),
+    )?;
     Ok(())
 }
 
 async fn create_url(
-    Extension(grpc_channel): Extension<Channel>,
+    Extension(backend): Extension<Backend>,
+    identity: Option<Extension<Identity>>,
     Json(payload): Json<CreateUrlRequest>,
-) -> Result<(StatusCode, Json<UrlCreated>), StatusCode> {
-    let mut client = ShortenUrlClient::new(grpc_channel.clone());
-    let grpc_request = Request::new(OriginalUrl {
-        url: payload.url.clone(),
-    });
-
-    let response = client
-        .create_shortened_url(grpc_request)
-        .await
-        .map_err(ApiError::from)?;
+) -> Result<(StatusCode, Json<UrlCreated>), ApiError> {
+    validate_url(&payload.url).map_err(ApiError::BadRequest)?;
+    let identity = identity.map(|Extension(identity)| identity);
+    let response = backend
+        .call(Retry::Never, |channel| {
+            let request = backend.request(
+                OriginalUrl {
+                    url: payload.url.clone(),
+                },
+                identity.as_ref(),
+            );
+            async move {
+                ShortenUrlClient::new(channel)
+                    .create_shortened_url(request)
+                    .await
+            }
+        })
+        .await?;
     let ShortenedUrl {
         id,
         original_url,
@@ -104,25 +201,29 @@ async fn create_url(
 }
 
 async fn delete_url(
-    Extension(grpc_channel): Extension<Channel>,
+    Extension(backend): Extension<Backend>,
+    identity: Option<Extension<Identity>>,
     Json(payload): Json<DeleteUrlRequest>,
-) -> Result<(StatusCode, Json<UrlDeleted>), StatusCode> {
-    let mut client = ShortenUrlClient::new(grpc_channel);
-    let grpc_request = Request::new(OriginalUrl {
-        url: payload.url.clone(),
-    });
-
-    match client.delete_shortened_url(grpc_request).await {
-        Ok(response) => {
-            let DeleteResponse { message, success } = response.into_inner();
-            Ok((StatusCode::OK, Json(UrlDeleted { message, success })))
-        }
-        Err(err) => {
-            error!("gRPC call failed: {:?}", err);
-            let status_code: StatusCode = ApiError::GrpcError(err).into();
-            Err(status_code)
-        }
-    }
+) -> Result<(StatusCode, Json<UrlDeleted>), ApiError> {
+    let identity = identity.map(|Extension(identity)| identity);
+    let response = backend
+        .call(Retry::Never, |channel| {
+            let request = backend.request(
+                OriginalUrl {
+                    url: payload.url.clone(),
+                },
+                identity.as_ref(),
+            );
+            async move {
+                ShortenUrlClient::new(channel)
+                    .delete_shortened_url(request)
+                    .await
+            }
+        })
+        .await?;
+    let DeleteResponse { message, success } = response.into_inner();
+
+    Ok((StatusCode::OK, Json(UrlDeleted { message, success })))
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,271 @@
+use crate::auth::Identity;
+use crate::backend::{Backend, Retry};
+use crate::echourl::shorten_url_client::ShortenUrlClient;
+use crate::echourl::{ListUrlsRequest, OriginalUrl, ShortenedUrl, Slug, UpdateUrlRequest};
+use crate::error::{ApiError, Problem};
+use crate::extract::{Json, Path, Query};
+use crate::idempotency::idempotency;
+use axum::handler::Handler;
+use axum::http::header::LOCATION;
+use axum::http::StatusCode;
+use axum::middleware::from_fn;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Extension, Router};
+use serde::{Deserialize, Serialize};
+use shared::validation::validate_url;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "EchoURL API", version = "1"),
+    paths(create_url, list_urls, get_url, update_url, delete_url),
+    components(schemas(CreateUrl, UpdateUrl, UrlResource, UrlPage, Problem)),
+    tags((name = "urls", description = "Shortened URL management"))
+)]
+pub struct ApiDoc;
+
+/// `/v1` resource routes, the OpenAPI document and the Swagger UI page.
+pub fn router() -> Router {
+    Router::new()
+        .route(
+            "/v1/urls",
+            get(list_urls).post(create_url.layer(from_fn(idempotency))),
+        )
+        .route(
+            "/v1/urls/{slug}",
+            get(get_url).patch(update_url).delete(delete_url),
+        )
+        .merge(SwaggerUi::new("/v1/docs").url("/v1/openapi.json", ApiDoc::openapi()))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateUrl {
+    /// Destination the short link redirects to.
+    url: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateUrl {
+    /// New destination for the short link.
+    url: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListParams {
+    /// Maximum number of links to return (default 20, at most 100).
+    page_size: Option<u32>,
+    /// `next_page_token` from the previous page.
+    page_token: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UrlResource {
+    id: i32,
+    original_url: String,
+    slug: String,
+    clicks: i32,
+    created_at: String,
+}
+
+impl From<ShortenedUrl> for UrlResource {
+    fn from(url: ShortenedUrl) -> Self {
+        Self {
+            id: url.id,
+            original_url: url.original_url,
+            slug: url.shortened_url,
+            clicks: url.clicks,
+            created_at: url.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UrlPage {
+    items: Vec<UrlResource>,
+    /// Absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_page_token: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/urls",
+    tag = "urls",
+    request_body = CreateUrl,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Replays the original response when a create is retried"),
+    ),
+    responses(
+        (status = 201, description = "Short link created", body = UrlResource),
+        (status = 409, description = "A request with the same idempotency key is in progress", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Idempotency key reused with a different body", body = Problem, content_type = "application/problem+json"),
+        (status = 400, description = "Invalid URL", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn create_url(
+    Extension(backend): Extension<Backend>,
+    identity: Option<Extension<Identity>>,
+    Json(payload): Json<CreateUrl>,
+) -> Result<impl IntoResponse, ApiError> {
+    validate_url(&payload.url).map_err(ApiError::BadRequest)?;
+    let identity = identity.map(|Extension(identity)| identity);
+    let response = backend
+        .call(Retry::Never, |channel| {
+            let request = backend.request(
+                OriginalUrl {
+                    url: payload.url.clone(),
+                },
+                identity.as_ref(),
+            );
+            async move {
+                ShortenUrlClient::new(channel)
+                    .create_shortened_url(request)
+                    .await
+            }
+        })
+        .await?;
+
+    let resource = UrlResource::from(response.into_inner());
+    let location = format!("/v1/urls/{}", resource.slug);
+    Ok((StatusCode::CREATED, [(LOCATION, location)], Json(resource)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/urls",
+    tag = "urls",
+    params(ListParams),
+    responses(
+        (status = 200, description = "A page of short links", body = UrlPage),
+        (status = 400, description = "Invalid page token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn list_urls(
+    Extension(backend): Extension<Backend>,
+    identity: Option<Extension<Identity>>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<UrlPage>, ApiError> {
+    let identity = identity.map(|Extension(identity)| identity);
+    let message = ListUrlsRequest {
+        page_size: params.page_size.unwrap_or_default(),
+        page_token: params.page_token.unwrap_or_default(),
+    };
+    let response = backend
+        .call(Retry::Idempotent, |channel| {
+            let request = backend.request(message.clone(), identity.as_ref());
+            async move {
+                ShortenUrlClient::new(channel)
+                    .list_shortened_urls(request)
+                    .await
+            }
+        })
+        .await?
+        .into_inner();
+
+    Ok(Json(UrlPage {
+        items: response.urls.into_iter().map(UrlResource::from).collect(),
+        next_page_token: Some(response.next_page_token).filter(|token| !token.is_empty()),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/urls/{slug}",
+    tag = "urls",
+    params(("slug" = String, Path, description = "Short link slug")),
+    responses(
+        (status = 200, description = "The short link", body = UrlResource),
+        (status = 404, description = "Unknown slug", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn get_url(
+    Extension(backend): Extension<Backend>,
+    identity: Option<Extension<Identity>>,
+    Path(slug): Path<String>,
+) -> Result<Json<UrlResource>, ApiError> {
+    let identity = identity.map(|Extension(identity)| identity);
+    let response = backend
+        .call(Retry::Idempotent, |channel| {
+            let request = backend.request(Slug { slug: slug.clone() }, identity.as_ref());
+            async move {
+                ShortenUrlClient::new(channel)
+                    .get_shortened_url(request)
+                    .await
+            }
+        })
+        .await?;
+
+    Ok(Json(response.into_inner().into()))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/v1/urls/{slug}",
+    tag = "urls",
+    params(("slug" = String, Path, description = "Short link slug")),
+    request_body = UpdateUrl,
+    responses(
+        (status = 200, description = "The updated short link", body = UrlResource),
+        (status = 400, description = "Invalid URL", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown slug", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn update_url(
+    Extension(backend): Extension<Backend>,
+    identity: Option<Extension<Identity>>,
+    Path(slug): Path<String>,
+    Json(payload): Json<UpdateUrl>,
+) -> Result<Json<UrlResource>, ApiError> {
+    validate_url(&payload.url).map_err(ApiError::BadRequest)?;
+    let identity = identity.map(|Extension(identity)| identity);
+    let message = UpdateUrlRequest {
+        slug,
+        url: payload.url,
+    };
+    // Setting the same destination again has the same effect, so updates can be retried.
+    let response = backend
+        .call(Retry::Idempotent, |channel| {
+            let request = backend.request(message.clone(), identity.as_ref());
+            async move {
+                ShortenUrlClient::new(channel)
+                    .update_shortened_url(request)
+                    .await
+            }
+        })
+        .await?;
+
+    Ok(Json(response.into_inner().into()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/urls/{slug}",
+    tag = "urls",
+    params(("slug" = String, Path, description = "Short link slug")),
+    responses(
+        (status = 204, description = "Short link deleted"),
+        (status = 404, description = "Unknown slug", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn delete_url(
+    Extension(backend): Extension<Backend>,
+    identity: Option<Extension<Identity>>,
+    Path(slug): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let identity = identity.map(|Extension(identity)| identity);
+    backend
+        .call(Retry::Never, |channel| {
+            let request = backend.request(Slug { slug: slug.clone() }, identity.as_ref());
+            async move {
+                ShortenUrlClient::new(channel)
+                    .delete_shortened_url_by_slug(request)
+                    .await
+            }
+        })
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
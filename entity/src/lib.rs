@@ -1,3 +1,6 @@
+pub mod click_outbox;
+pub mod stale_cache_entry;
 pub mod url;
+pub mod url_click_daily;
 
 pub use sea_orm::entity::prelude::*;
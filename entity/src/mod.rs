@@ -2,4 +2,7 @@
 
 pub mod prelude;
 
+pub mod click_outbox;
+pub mod stale_cache_entry;
 pub mod url;
+pub mod url_click_daily;
@@ -1,3 +1,6 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.1
 
+pub use super::click_outbox::Entity as ClickOutbox;
+pub use super::stale_cache_entry::Entity as StaleCacheEntry;
 pub use super::url::Entity as Url;
+pub use super::url_click_daily::Entity as UrlClickDaily;
@@ -1,12 +1,20 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20250320_000001_create_url_click_daily;
+mod m20251018_000001_create_click_outbox;
+mod m20251019_000001_create_stale_cache_entry;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20250320_000001_create_url_click_daily::Migration),
+            Box::new(m20251018_000001_create_click_outbox::Migration),
+            Box::new(m20251019_000001_create_stale_cache_entry::Migration),
+        ]
     }
 }
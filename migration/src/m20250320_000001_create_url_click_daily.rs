@@ -0,0 +1,49 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UrlClickDaily::Table)
+                    .col(pk_auto(UrlClickDaily::Id))
+                    .col(string(UrlClickDaily::Slug).not_null())
+                    .col(date(UrlClickDaily::Day).not_null())
+                    .col(integer(UrlClickDaily::Clicks).default(0).not_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_url_click_daily_slug_day")
+                    .table(UrlClickDaily::Table)
+                    .col(UrlClickDaily::Slug)
+                    .col(UrlClickDaily::Day)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(UrlClickDaily::Table).to_owned())
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum UrlClickDaily {
+    Table,
+    Id,
+    Slug,
+    Day,
+    Clicks,
+}
@@ -0,0 +1,40 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ClickOutbox::Table)
+                    .col(big_integer(ClickOutbox::Id).auto_increment().primary_key())
+                    .col(string(ClickOutbox::Slug).not_null())
+                    .col(text(ClickOutbox::Payload).not_null())
+                    .col(json_binary(ClickOutbox::Headers).not_null())
+                    .col(timestamp_with_time_zone(ClickOutbox::ClickedAt).not_null())
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ClickOutbox::Table).to_owned())
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum ClickOutbox {
+    Table,
+    Id,
+    Slug,
+    Payload,
+    Headers,
+    ClickedAt,
+}
@@ -0,0 +1,74 @@
+use crate::{handle_redirect, AppState};
+use anyhow::{bail, Result};
+use axum::extract::{Path, State};
+use axum::response::IntoResponse;
+use redis::AsyncCommands;
+use shared::cache::{legacy_slug_key, slug_key};
+use std::time::{Duration, Instant};
+
+/// Times `requests` calls of [`handle_redirect`] for `slug` as served by each tier (local
+/// cache, Redis, Postgres), evicting the tiers in front of it before every call, and prints
+/// the latency distribution. The calls publish no clicks.
+pub async fn bench(state: &AppState, slug: &str, requests: usize) -> Result<()> {
+    println!(
+        "{:<10} {:>10} {:>10} {:>10} {:>10}",
+        "tier", "mean", "p50", "p99", "max"
+    );
+    for tier in ["local", "redis", "database"] {
+        // Fills every tier, so that each measured call is served by `tier`.
+        redirect(state, slug).await?;
+
+        let mut samples = Vec::with_capacity(requests);
+        for _ in 0..requests {
+            evict(state, slug, tier).await?;
+            let started = Instant::now();
+            redirect(state, slug).await?;
+            samples.push(started.elapsed());
+        }
+        report(tier, &mut samples);
+    }
+    Ok(())
+}
+
+async fn redirect(state: &AppState, slug: &str) -> Result<()> {
+    let response = handle_redirect(State(state.clone()), Path(slug.to_string()))
+        .await?
+        .into_response();
+    if !response.status().is_redirection() {
+        bail!("`{}` did not redirect: {}", slug, response.status());
+    }
+    Ok(())
+}
+
+async fn evict(state: &AppState, slug: &str, tier: &str) -> Result<()> {
+    if tier == "local" {
+        return Ok(());
+    }
+    state.local_cache.invalidate(slug).await;
+    if tier == "database" {
+        // Without Redis every lookup reaches Postgres anyway.
+        if let Ok(mut connection) = state.redis.connection() {
+            // One key at a time, as they live in different cluster slots.
+            connection.del::<_, ()>(slug_key(slug)).await?;
+            connection.del::<_, ()>(legacy_slug_key(slug)).await?;
+        }
+    }
+    Ok(())
+}
+
+fn report(tier: &str, samples: &mut [Duration]) {
+    if samples.is_empty() {
+        return;
+    }
+    samples.sort();
+    let percentile = |q: f64| samples[((samples.len() - 1) as f64 * q).round() as usize];
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    println!(
+        "{:<10} {:>10} {:>10} {:>10} {:>10}",
+        tier,
+        format!("{:.1?}", mean),
+        format!("{:.1?}", percentile(0.5)),
+        format!("{:.1?}", percentile(0.99)),
+        format!("{:.1?}", samples[samples.len() - 1]),
+    );
+}
@@ -0,0 +1,187 @@
+use crate::filter::SlugFilter;
+use moka::future::Cache;
+use shared::cache::CacheRecord;
+use shared::config::CacheConfig;
+use shared::connection::RedisPool;
+use shared::DbPool;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tracing::{error, info, warn};
+
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+const INVALIDATION_STRIPES: usize = 256;
+
+/// In-process copy of recently resolved slugs, consulted before Redis. A record without a
+/// destination marks a slug that does not exist.
+#[derive(Clone)]
+pub struct LocalCache {
+    entries: Cache<String, CacheRecord>,
+    /// Invalidations so far, counted per stripe of slugs.
+    invalidations: Arc<[AtomicU64]>,
+}
+
+/// Taken before a slug is read from Redis or Postgres, so that the record read is not
+/// cached over an invalidation that arrived meanwhile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Generation(u64);
+
+impl LocalCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            entries: Cache::builder()
+                .max_capacity(config.local_capacity)
+                .time_to_live(config.local_ttl())
+                .build(),
+            invalidations: (0..INVALIDATION_STRIPES)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+
+    pub fn generation(&self, slug: &str) -> Generation {
+        Generation(self.stripe(slug).load(Ordering::SeqCst))
+    }
+
+    pub async fn get(&self, slug: &str) -> Option<CacheRecord> {
+        self.entries.get(slug).await
+    }
+
+    /// Caches `record`, read at `read_at`, unless `slug` has been invalidated since.
+    pub async fn insert(&self, slug: String, record: CacheRecord, read_at: Generation) {
+        self.entries.insert(slug.clone(), record).await;
+        // An invalidation that ran before the insert found nothing to remove.
+        if self.generation(&slug) != read_at {
+            self.entries.invalidate(&slug).await;
+        }
+    }
+
+    pub async fn invalidate(&self, slug: &str) {
+        self.stripe(slug).fetch_add(1, Ordering::SeqCst);
+        self.entries.invalidate(slug).await;
+    }
+
+    fn clear(&self) {
+        for stripe in self.invalidations.iter() {
+            stripe.fetch_add(1, Ordering::SeqCst);
+        }
+        self.entries.invalidate_all();
+    }
+
+    fn stripe(&self, slug: &str) -> &AtomicU64 {
+        let mut hasher = DefaultHasher::new();
+        slug.hash(&mut hasher);
+        &self.invalidations[hasher.finish() as usize % self.invalidations.len()]
+    }
+
+    /// Follows the slugs announced by shortener_service: they are dropped from the cache and
+    /// added to `filter`. Announcements made while disconnected are lost, so the filter is
+    /// suspended while the subscription is down, and the cache is cleared and the filter
+    /// rebuilt on every (re)subscribe.
+    pub async fn listen_for_invalidations(
+        self,
+        filter: SlugFilter,
+        db: DbPool,
+        redis: RedisPool,
+        channel: String,
+    ) {
+        loop {
+            if let Err(e) = self.follow(&filter, &db, &redis, &channel).await {
+                warn!("Cache invalidation subscription failed: {:?}", e);
+            }
+            filter.suspend();
+            self.clear();
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn follow(
+        &self,
+        filter: &SlugFilter,
+        db: &DbPool,
+        redis: &RedisPool,
+        channel: &str,
+    ) -> redis::RedisResult<()> {
+        let mut pubsub = redis.pubsub().await?;
+        pubsub.subscribe(channel).await?;
+        self.clear();
+        info!("Subscribed to cache invalidations on `{}`", channel);
+        // Announcements queue up in the subscription meanwhile.
+        if let Err(e) = filter.resume(db).await {
+            error!("Failed to rebuild slug filter: {:?}", e);
+        }
+
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            match message.get_payload::<String>() {
+                Ok(slug) => {
+                    filter.insert(&slug);
+                    self.invalidate(&slug).await;
+                }
+                Err(e) => warn!("Ignoring malformed invalidation: {:?}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> LocalCache {
+        LocalCache::new(&CacheConfig::default())
+    }
+
+    #[tokio::test]
+    async fn invalidation_removes_the_entry() {
+        let cache = cache();
+        let record = CacheRecord::found("https://example.com/a");
+        cache
+            .insert("a".into(), record.clone(), cache.generation("a"))
+            .await;
+        cache
+            .insert("b".into(), record.clone(), cache.generation("b"))
+            .await;
+        assert_eq!(cache.get("a").await, Some(record.clone()));
+
+        cache.invalidate("a").await;
+        assert_eq!(cache.get("a").await, None);
+        assert_eq!(cache.get("b").await, Some(record));
+    }
+
+    #[tokio::test]
+    async fn a_record_read_before_an_invalidation_is_not_cached() {
+        let cache = cache();
+        let read_at = cache.generation("a");
+        // The link changes while its old destination is being read.
+        cache.invalidate("a").await;
+        cache
+            .insert(
+                "a".into(),
+                CacheRecord::found("https://old.example"),
+                read_at,
+            )
+            .await;
+        assert_eq!(cache.get("a").await, None);
+
+        let record = CacheRecord::found("https://new.example");
+        cache
+            .insert("a".into(), record.clone(), cache.generation("a"))
+            .await;
+        assert_eq!(cache.get("a").await, Some(record));
+    }
+
+    #[tokio::test]
+    async fn clearing_covers_reads_in_progress() {
+        let cache = cache();
+        let read_at = cache.generation("a");
+        cache.clear();
+        cache
+            .insert("a".into(), CacheRecord::missing(), read_at)
+            .await;
+        assert_eq!(cache.get("a").await, None);
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Result};
+use bloomfilter::Bloom;
+use entity::url;
+use metrics::gauge;
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QuerySelect, TransactionTrait,
+};
+use shared::config::CacheConfig;
+use shared::prometheus::timed;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tracing::{error, info};
+
+struct Inner {
+    /// `None` until built and while suspended, during which every slug may exist.
+    bloom: Option<Bloom<str>>,
+    /// Set while announcements of new slugs cannot be received.
+    suspended: bool,
+    /// Slugs announced while a rebuild runs, replayed into the new filter.
+    pending: Option<Vec<String>>,
+}
+
+/// Probabilistic set of existing slugs: a slug it does not contain certainly does not exist,
+/// so the lookup can be answered without Postgres.
+#[derive(Clone)]
+pub struct SlugFilter {
+    inner: Arc<RwLock<Inner>>,
+    rebuilding: Arc<Mutex<()>>,
+    expected_slugs: usize,
+    false_positive_rate: f64,
+}
+
+impl SlugFilter {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Inner {
+                bloom: None,
+                suspended: true,
+                pending: None,
+            })),
+            rebuilding: Arc::new(Mutex::new(())),
+            expected_slugs: config.filter_expected_slugs,
+            false_positive_rate: config.filter_false_positive_rate,
+        }
+    }
+
+    pub fn may_exist(&self, slug: &str) -> bool {
+        let inner = self.inner.read().unwrap_or_else(|e| e.into_inner());
+        inner.bloom.as_ref().is_none_or(|bloom| bloom.check(slug))
+    }
+
+    pub fn insert(&self, slug: &str) {
+        let mut inner = self.lock();
+        if let Some(bloom) = inner.bloom.as_mut() {
+            bloom.set(slug);
+        }
+        if let Some(pending) = inner.pending.as_mut() {
+            pending.push(slug.to_string());
+        }
+    }
+
+    /// Stops rejecting slugs until [`SlugFilter::resume`], for while new slugs may go
+    /// unnoticed.
+    pub fn suspend(&self) {
+        let mut inner = self.lock();
+        inner.suspended = true;
+        inner.bloom = None;
+    }
+
+    /// Rebuilds the filter once announcements of new slugs are being received again.
+    pub async fn resume(&self, db: &DatabaseConnection) -> Result<()> {
+        self.lock().suspended = false;
+        self.rebuild(db).await
+    }
+
+    /// Replaces the filter with one built from the `url` table, unless it is suspended.
+    pub async fn rebuild(&self, db: &DatabaseConnection) -> Result<()> {
+        let _rebuilding = self.rebuilding.lock().await;
+        self.lock().pending = Some(Vec::new());
+        let built = self.build(db).await;
+        self.install(built)
+    }
+
+    /// Swaps in a freshly built filter with the slugs announced while it was built, unless
+    /// the filter was suspended meanwhile.
+    fn install(&self, built: Result<(Bloom<str>, usize)>) -> Result<()> {
+        let mut inner = self.lock();
+        let pending = inner.pending.take().unwrap_or_default();
+        let (mut bloom, count) = built?;
+        if inner.suspended {
+            return Ok(());
+        }
+        for slug in &pending {
+            bloom.set(slug.as_str());
+        }
+        inner.bloom = Some(bloom);
+        gauge!("slug_filter_items").set((count + pending.len()) as f64);
+        info!("Rebuilt slug filter with {} slugs", count);
+        Ok(())
+    }
+
+    /// Rebuilds the filter every `filter_rebuild_secs`.
+    pub async fn refresh_periodically(self, db: Arc<DatabaseConnection>, config: CacheConfig) {
+        let mut interval = tokio::time::interval(config.filter_rebuild_interval());
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.rebuild(&db).await {
+                error!("Failed to rebuild slug filter: {:?}", e);
+            }
+        }
+    }
+
+    async fn build(&self, db: &DatabaseConnection) -> Result<(Bloom<str>, usize)> {
+        // Reading every slug can take longer than `database.statement_timeout_ms`, which is
+        // meant for the queries behind requests. The primary is read so that no slug is missed
+        // for replication lag.
+        let txn = db.begin().await?;
+        txn.execute_unprepared("SET LOCAL statement_timeout = 0")
+            .await?;
+        let rows = timed("count_urls", url::Entity::find().count(&txn)).await? as usize;
+        // Leave headroom for the slugs created until the next rebuild.
+        let capacity = self.expected_slugs.max(rows * 2);
+        let mut bloom = Bloom::new_for_fp_rate(capacity, self.false_positive_rate)
+            .map_err(|e| anyhow!("Invalid slug filter size: {}", e))?;
+
+        let mut slugs = url::Entity::find()
+            .select_only()
+            .column(url::Column::Shortened)
+            .into_tuple::<String>()
+            .stream(&txn)
+            .await?;
+        let mut count = 0;
+        while let Some(slug) = slugs.next().await {
+            bloom.set(slug?.as_str());
+            count += 1;
+        }
+        drop(slugs);
+        txn.commit().await?;
+        Ok((bloom, count))
+    }
+
+    fn lock(&self) -> RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn built(slugs: &[&str]) -> Result<(Bloom<str>, usize)> {
+        let mut bloom = Bloom::new_for_fp_rate(100, 1e-9).unwrap();
+        for slug in slugs {
+            bloom.set(*slug);
+        }
+        Ok((bloom, slugs.len()))
+    }
+
+    /// The state of a filter whose rebuild has started, as [`SlugFilter::rebuild`] leaves it
+    /// while the `url` table is read.
+    fn rebuilding() -> SlugFilter {
+        let filter = SlugFilter::new(&CacheConfig::default());
+        let mut inner = filter.lock();
+        inner.suspended = false;
+        inner.pending = Some(Vec::new());
+        drop(inner);
+        filter
+    }
+
+    #[test]
+    fn rejects_nothing_until_built() {
+        let filter = SlugFilter::new(&CacheConfig::default());
+        assert!(filter.may_exist("anything"));
+    }
+
+    #[test]
+    fn keeps_slugs_announced_during_a_rebuild() {
+        let filter = rebuilding();
+        filter.insert("new");
+        filter.install(built(&["old"])).unwrap();
+
+        assert!(filter.may_exist("old"));
+        assert!(filter.may_exist("new"));
+        assert!(!filter.may_exist("unknown"));
+
+        filter.insert("later");
+        assert!(filter.may_exist("later"));
+    }
+
+    #[test]
+    fn suspending_drops_the_filter_even_mid_rebuild() {
+        let filter = rebuilding();
+        filter.suspend();
+        filter.install(built(&["old"])).unwrap();
+        assert!(filter.may_exist("unknown"));
+
+        let filter = rebuilding();
+        filter.install(built(&["old"])).unwrap();
+        filter.suspend();
+        assert!(filter.may_exist("unknown"));
+    }
+
+    #[test]
+    fn failed_rebuild_keeps_the_previous_filter() {
+        let filter = rebuilding();
+        filter.install(built(&["old"])).unwrap();
+
+        filter.lock().pending = Some(Vec::new());
+        filter.insert("new");
+        assert!(filter.install(Err(anyhow!("query failed"))).is_err());
+
+        assert!(filter.lock().pending.is_none());
+        assert!(filter.may_exist("new"));
+        assert!(!filter.may_exist("unknown"));
+    }
+}
@@ -1,22 +1,49 @@
+use crate::bench::bench;
+use crate::cache::LocalCache;
+use crate::filter::SlugFilter;
+use crate::outbox::{ClickEvent, ClickOutbox};
+use crate::single_flight::SingleFlight;
+use crate::warm_up::warm_up;
 use anyhow::{Context, Result};
 use axum::extract::State;
 use axum::http::StatusCode;
+use axum::middleware::{from_fn, from_fn_with_state};
 use axum::response::{IntoResponse, Redirect};
 use axum::{extract::Path, routing::get, Router};
+use clap::{Parser, Subcommand};
 use entity::url;
+use metrics::{counter, histogram};
+use rand::Rng;
 use rdkafka::config::ClientConfig;
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use redis::AsyncCommands;
-use sea_orm::sqlx::types::chrono::Utc;
-use sea_orm::{ColumnTrait, EntityTrait};
-use sea_orm::{DatabaseConnection, QueryFilter};
-use shared::connect_db;
-use shared::connection::connect_redis;
-use std::env;
+use rdkafka::producer::{FutureProducer, Producer};
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use sea_orm::QueryFilter;
+use sea_orm::{ColumnTrait, DbErr, EntityTrait};
+use shared::cache::{legacy_slug_key, slug_key, CacheRecord};
+use shared::config::{figment, Cli, Config, KafkaConfig, ServiceDefaults};
+use shared::connection::{connect_databases, connect_redis, Databases, RedisPool};
+use shared::health::{self, Readiness};
+use shared::prometheus::{self, timed};
+use shared::rate_limit::{rate_limit, RateLimiter};
+use shared::shutdown::Shutdown;
+use shared::telemetry;
+use shared::trace_context::{self, propagate};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tracing::{error, info, Level};
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, warn};
+
+mod bench;
+mod cache;
+mod filter;
+mod outbox;
+mod single_flight;
+mod warm_up;
+
+const KAFKA_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Error, Debug)]
 pub enum RedirectError {
@@ -24,7 +51,7 @@ pub enum RedirectError {
     NotFound,
 
     #[error("Database error: {0}")]
-    DatabaseError(#[from] sea_orm::DbErr),
+    DatabaseError(#[from] Arc<sea_orm::DbErr>),
 
     #[error("Redis error: {0}")]
     RedisError(#[from] redis::RedisError),
@@ -52,40 +79,180 @@ impl IntoResponse for RedirectError {
 
 #[derive(Clone)]
 struct AppState {
-    db: Arc<DatabaseConnection>,
-    redis: Arc<redis::Client>,
+    db: Databases,
+    redis: RedisPool,
+    local_cache: LocalCache,
+    slug_filter: SlugFilter,
+    lookups: Arc<SingleFlight<Result<CacheRecord, Arc<DbErr>>>>,
+    lookup_duration: Arc<MovingAverage>,
     kafka_producer: FutureProducer,
+    /// Absent while benchmarking, whose redirects are not clicks.
+    outbox: Option<ClickOutbox>,
+    config: Arc<Config>,
+}
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    #[command(flatten)]
+    cli: Cli,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Preload Redis with the most clicked and most recent links, then exit.
+    WarmCache,
+    /// Time redirects of an existing slug from each cache tier and print the latencies,
+    /// then exit.
+    Bench {
+        slug: String,
+        /// Redirects timed per tier.
+        #[arg(long, default_value_t = 1000)]
+        requests: usize,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    unsafe {
-        env::set_var("RUST_LOG", "info");
+    let args = Args::parse();
+    let defaults = ServiceDefaults {
+        name: "redirect_service",
+        listen_addr: "0.0.0.0:4000",
+        metrics_addr: "0.0.0.0:9103",
+    };
+    let config = Arc::new(Config::from_figment(&figment(&defaults, &args.cli)?)?);
+    let _telemetry =
+        telemetry::init(&config.telemetry).context("Failed to initialise telemetry")?;
+
+    let db = connect_databases(&config.database)
+        .await
+        .context("Database connection failed")?;
+    let redis = connect_redis(&config.redis)
+        .await
+        .context("Redis connection failed")?;
+
+    if let Some(Command::WarmCache) = args.command {
+        warm_up(&db, &redis, &config).await?;
+        return Ok(());
+    }
+    if config.cache.warm_up_on_start && args.command.is_none() {
+        let (db, redis, config) = (db.clone(), redis.clone(), config.clone());
+        tokio::spawn(async move {
+            if let Err(e) = warm_up(&db, &redis, &config).await {
+                error!("Cache warm-up failed: {:?}", e);
+            }
+        });
     }
 
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+    let metrics = prometheus::install()?;
+    let shutdown = Shutdown::install(config.server.shutdown_grace_period());
+
+    let limiter = Arc::new(RateLimiter::new(
+        "redirect_service",
+        config.rate_limit.clone(),
+        Some(redis.clone()),
+    ));
 
-    let db = connect_db().await.context("Database connection failed")?;
-    let redis = connect_redis().await.context("Redis connection failed")?;
+    let local_cache = LocalCache::new(&config.cache);
+    let slug_filter = SlugFilter::new(&config.cache);
+    tokio::spawn(local_cache.clone().listen_for_invalidations(
+        slug_filter.clone(),
+        db.primary.clone(),
+        redis.clone(),
+        config.cache.invalidation_channel.clone(),
+    ));
+    tokio::spawn(
+        slug_filter
+            .clone()
+            .refresh_periodically(db.primary.clone(), config.cache.clone()),
+    );
+
+    let kafka_producer = create_kafka_producer(&config.kafka)?;
+    let outbox = match args.command {
+        Some(Command::Bench { .. }) => None,
+        _ => {
+            let outbox =
+                ClickOutbox::new(db.primary.clone(), kafka_producer.clone(), &config.kafka);
+            tokio::spawn(outbox.clone().relay());
+            Some(outbox)
+        }
+    };
 
     let state = AppState {
         db: db.clone(),
         redis: redis.clone(),
-        kafka_producer: create_kafka_producer(),
+        local_cache,
+        slug_filter,
+        lookups: Arc::new(SingleFlight::new()),
+        lookup_duration: Arc::new(MovingAverage::default()),
+        kafka_producer,
+        outbox,
+        config: config.clone(),
     };
 
+    if let Some(Command::Bench { slug, requests }) = &args.command {
+        bench(&state, slug, *requests).await?;
+        return Ok(());
+    }
+
+    let producer = state.kafka_producer.clone();
+    let mut readiness = Readiness::new()
+        .draining(&shutdown)
+        .postgres(db.primary.clone());
+    if db.has_replica() {
+        readiness = readiness.postgres_replica(db.replica.clone());
+    }
+    let readiness = readiness.redis_cache(redis.clone()).optional("kafka", {
+        let producer = producer.clone();
+        let topic = config.kafka.clicks_topic.clone();
+        move || kafka_ready(producer.clone(), topic.clone())
+    });
+
     let app = Router::new()
         .route("/{slug}", get(handle_redirect))
-        .with_state(state);
+        .route_layer(from_fn_with_state(limiter, rate_limit))
+        .layer(config.cors.layer())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(trace_context::http_span)
+                .on_response(telemetry::on_http_response),
+        )
+        .layer(from_fn(propagate))
+        .with_state(state)
+        .merge(health::router(readiness));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:4000")
+    let addr = config.server.listen_addr;
+    let listener = tokio::net::TcpListener::bind(addr)
         .await
-        .context("Failed to bind HTTP server to port 4000")?;
-    info!("🚀 HTTP server listening on 0.0.0.0:4000");
+        .with_context(|| format!("Failed to bind HTTP server to {}", addr))?;
+    info!("🚀 HTTP server listening on {}", addr);
 
-    axum::serve(listener, app)
-        .await
-        .context("HTTP server error")?;
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.requested());
+    tokio::try_join!(
+        shutdown.drain("HTTP server", async {
+            server.await.context("HTTP server error")
+        }),
+        shutdown.drain(
+            "Metrics server",
+            prometheus::serve(
+                config.server.metrics_addr,
+                prometheus::router(metrics),
+                &shutdown
+            )
+        ),
+    )?;
+
+    // Deliver click events that are still queued in the producer.
+    tokio::task::spawn_blocking(move || producer.flush(KAFKA_FLUSH_TIMEOUT))
+        .await?
+        .context("Failed to flush Kafka producer")?;
     Ok(())
 }
 
@@ -93,74 +260,226 @@ async fn handle_redirect(
     State(state): State<AppState>,
     Path(slug): Path<String>,
 ) -> Result<impl IntoResponse, RedirectError> {
-    let db = state.db.clone();
+    let started = Instant::now();
 
-    let redis_client = state.redis.clone();
-    let mut redis_conn = redis_client
-        .get_multiplexed_async_connection()
-        .await
-        .map_err(|e| {
-            error!("Failed to get Redis connection: {:?}", e);
-            RedirectError::RedisError(e)
-        })?;
-
-    let cache_key = format!("slug:{}", slug);
-    match redis_conn.get::<_, String>(&cache_key).await {
-        Ok(original_url) => {
+    if let Some(record) = state.local_cache.get(&slug).await {
+        counter!("redirect_cache_hits_total", "tier" => "local").increment(1);
+        let url = record.destination().ok_or_else(|| not_found("local"))?;
+        publish_kafka_event(&state, slug.clone());
+        histogram!("redirect_duration_seconds", "cache" => "local").record(started.elapsed());
+        return Ok(Redirect::permanent(url));
+    }
+    counter!("redirect_cache_misses_total", "tier" => "local").increment(1);
+
+    let read_at = state.local_cache.generation(&slug);
+    match lookup_redis(&state.redis, &slug).await {
+        Ok((Some(record), ttl_ms)) => {
             info!("Cache hit for `{}`", slug);
-            publish_kafka_event(&state.kafka_producer, slug.clone()).await;
-            return Ok(Redirect::permanent(&original_url));
+            counter!("redirect_cache_hits_total", "tier" => "redis").increment(1);
+            if refresh_early(&state, ttl_ms) {
+                counter!("redirect_early_refreshes_total").increment(1);
+                let (state, slug) = (state.clone(), slug.clone());
+                tokio::spawn(async move { load(&state, &slug).await });
+            }
+
+            state
+                .local_cache
+                .insert(slug.clone(), record.clone(), read_at)
+                .await;
+            let url = record.destination().ok_or_else(|| not_found("redis"))?;
+            publish_kafka_event(&state, slug.clone());
+            histogram!("redirect_duration_seconds", "cache" => "redis").record(started.elapsed());
+            return Ok(Redirect::permanent(url));
+        }
+        Ok((None, _)) => {
+            counter!("redirect_cache_misses_total", "tier" => "redis").increment(1);
         }
+        // Redis is only a cache: without it, lookups go straight to Postgres.
         Err(e) => {
-            error!("Cache miss for `{}`: {:?}", slug, e);
+            warn!("Cache lookup for `{}` failed: {:?}", slug, e);
+            counter!("redirect_cache_errors_total", "tier" => "redis").increment(1);
         }
     }
 
-    let url_entry = url::Entity::find()
-        .filter(url::Column::Shortened.eq(slug.clone()))
-        .one(&*db)
+    if !state.slug_filter.may_exist(&slug) {
+        return Err(not_found("filter"));
+    }
+
+    let record = load(&state, &slug).await?;
+    let url = record.destination().ok_or_else(|| not_found("database"))?;
+    publish_kafka_event(&state, slug.clone());
+    histogram!("redirect_duration_seconds", "cache" => "miss").record(started.elapsed());
+
+    Ok(Redirect::temporary(url))
+}
+
+/// Looks `slug` up in Postgres and refills both cache tiers. Concurrent lookups of one slug
+/// share a single query.
+async fn load(state: &AppState, slug: &str) -> Result<CacheRecord, Arc<DbErr>> {
+    state
+        .lookups
+        .run(slug, || async {
+            let read_at = state.local_cache.generation(slug);
+            let started = Instant::now();
+            let url_entry = find_by_slug(&state.db, slug).await.map_err(Arc::new)?;
+            state.lookup_duration.record(started.elapsed());
+
+            let record = match url_entry {
+                Some(url_entry) => {
+                    info!("Queried DB, caching `{}`", url_entry.original);
+                    let record = CacheRecord::found(&url_entry.original);
+                    if let Err(e) = cache_in_redis(state, slug, &record).await {
+                        error!("Failed to cache `{}` in Redis: {:?}", slug, e);
+                    }
+                    record
+                }
+                // Unknown slugs are remembered briefly so that repeated lookups stay off Postgres.
+                None => match cache_missing(state, slug).await {
+                    Ok(true) => CacheRecord::missing(),
+                    // An entry appeared since the query, so the link was created meanwhile and
+                    // the next request finds it; a miss must not be cached locally either.
+                    Ok(false) => return Ok(CacheRecord::missing()),
+                    Err(e) => {
+                        error!("Failed to cache `{}` in Redis: {:?}", slug, e);
+                        CacheRecord::missing()
+                    }
+                },
+            };
+            state
+                .local_cache
+                .insert(slug.to_string(), record.clone(), read_at)
+                .await;
+            Ok(record)
+        })
         .await
-        .map_err(RedirectError::DatabaseError)?
-        .ok_or(RedirectError::NotFound)?;
+}
+
+/// Reads from the replica when there is one. A miss there is confirmed on the primary, as a
+/// link created a moment ago may not have replicated yet, and a failing replica is skipped.
+async fn find_by_slug(db: &Databases, slug: &str) -> Result<Option<url::Model>, DbErr> {
+    let query = || url::Entity::find().filter(url::Column::Shortened.eq(slug));
+    if db.has_replica() {
+        match timed("find_by_slug_replica", query().one(&*db.replica)).await {
+            Ok(Some(url_entry)) => return Ok(Some(url_entry)),
+            Ok(None) => {}
+            Err(e) => {
+                warn!(
+                    "Replica lookup of `{}` failed, using the primary: {:?}",
+                    slug, e
+                );
+                counter!("redirect_replica_errors_total").increment(1);
+            }
+        }
+    }
+    timed("find_by_slug", query().one(&*db.primary)).await
+}
 
-    info!("Queried DB, caching `{}`", url_entry.original.clone());
+/// The cached record of `slug` and its remaining lifetime in milliseconds, falling back to
+/// the entry under the legacy key.
+async fn lookup_redis(
+    redis: &RedisPool,
+    slug: &str,
+) -> redis::RedisResult<(Option<CacheRecord>, i64)> {
+    let mut connection = redis.connection()?;
+    for key in [slug_key(slug), legacy_slug_key(slug)] {
+        let (record, ttl_ms): (Option<CacheRecord>, i64) = redis::pipe()
+            .get(&key)
+            .pttl(&key)
+            .query_async(&mut connection)
+            .await?;
+        if record.is_some() {
+            return Ok((record, ttl_ms));
+        }
+    }
+    Ok((None, -2))
+}
 
+async fn cache_in_redis(state: &AppState, slug: &str, record: &CacheRecord) -> Result<()> {
+    let mut redis_conn = state.redis.connection()?;
     redis_conn
-        .set_ex::<_, _, ()>(
-            format!("slug:{}", slug.clone()),
-            url_entry.original.clone(),
-            86_400,
-        )
-        .await
-        .map_err(|e| {
-            error!("Failed to cache in Redis: {:?}", e);
-            RedirectError::InternalServerError("Redis cache error".into())
-        })?;
-    publish_kafka_event(&state.kafka_producer, slug.clone()).await;
+        .set_ex::<_, _, ()>(slug_key(slug), record, state.config.redis.cache_ttl_secs)
+        .await?;
+    Ok(())
+}
 
-    Ok(Redirect::temporary(&*url_entry.original))
+/// Caches that `slug` does not exist, unless an entry was written since it was looked up:
+/// that is a link created in the meantime, which a miss must not overwrite. Returns whether
+/// the miss was cached.
+async fn cache_missing(state: &AppState, slug: &str) -> Result<bool> {
+    let options = SetOptions::default()
+        .conditional_set(ExistenceCheck::NX)
+        .with_expiration(SetExpiry::EX(state.config.cache.negative_ttl_secs));
+    let mut redis_conn = state.redis.connection()?;
+    let stored: Option<String> = redis_conn
+        .set_options(slug_key(slug), CacheRecord::missing(), options)
+        .await?;
+    Ok(stored.is_some())
 }
 
-async fn publish_kafka_event(producer: &FutureProducer, slug: String) {
-    let event = format!(r#"{{"slug": "{}", "timestamp": "{}"}}"#, slug, Utc::now());
+/// Probabilistic early expiration ("XFetch"): the closer an entry is to expiring, and the
+/// longer it takes to reload, the likelier a request refreshes it ahead of time, so that a
+/// hot entry is reloaded once rather than by every request when it lapses.
+fn refresh_early(state: &AppState, ttl_ms: i64) -> bool {
+    let beta = state.config.cache.early_refresh_beta;
+    if ttl_ms < 0 || beta <= 0.0 {
+        return false;
+    }
+    let delta = state.lookup_duration.get().as_secs_f64();
+    let headroom = delta * beta * -rand::rng().random::<f64>().ln();
+    headroom * 1_000.0 >= ttl_ms as f64
+}
 
-    if let Err(e) = producer
-        .send(
-            FutureRecord::to("url_clicks").payload(&event).key(&slug),
-            Duration::from_secs(0),
-        )
-        .await
-    {
-        error!("Failed to send Kafka message: {:?}", e);
-    } else {
-        info!("Published click event for `{}` to Kafka", slug);
+/// Exponentially weighted moving average of a duration.
+#[derive(Default)]
+struct MovingAverage {
+    micros: AtomicU64,
+}
+
+impl MovingAverage {
+    fn record(&self, sample: Duration) {
+        let sample = sample.as_micros() as u64;
+        let _ = self
+            .micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(match average {
+                    0 => sample,
+                    average => (average * 4 + sample) / 5,
+                })
+            });
+    }
+
+    fn get(&self) -> Duration {
+        Duration::from_micros(self.micros.load(Ordering::Relaxed))
+    }
+}
+
+fn not_found(source: &'static str) -> RedirectError {
+    counter!("redirect_not_found_total", "source" => source).increment(1);
+    RedirectError::NotFound
+}
+
+fn publish_kafka_event(state: &AppState, slug: String) {
+    if let Some(outbox) = &state.outbox {
+        outbox.publish(ClickEvent::new(slug));
     }
 }
 
-fn create_kafka_producer() -> FutureProducer {
+/// Metadata requests block, so they run off the async workers.
+async fn kafka_ready(producer: FutureProducer, topic: String) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        producer
+            .client()
+            .fetch_metadata(Some(&topic), Duration::from_secs(1))
+            .map(|_| ())
+    })
+    .await?
+    .context("Kafka metadata request failed")
+}
+
+fn create_kafka_producer(config: &KafkaConfig) -> Result<FutureProducer> {
     ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        .set("message.timeout.ms", "5000")
+        .set("bootstrap.servers", &config.brokers)
+        .set("message.timeout.ms", config.message_timeout_ms.to_string())
         .create()
-        .expect("Kafka producer creation failed")
+        .context("Kafka producer creation failed")
 }
@@ -0,0 +1,408 @@
+use anyhow::Result;
+use entity::click_outbox;
+use metrics::{counter, gauge};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use sea_orm::entity::prelude::Json;
+use sea_orm::sqlx::types::chrono::{DateTime, Utc};
+use sea_orm::sqlx::{self, PgConnection};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set,
+};
+use shared::config::KafkaConfig;
+use shared::prometheus::timed;
+use shared::trace_context::TraceContext;
+use shared::DbPool;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn, Instrument};
+
+/// Held by whichever instance is relaying, so that events leave the outbox in order.
+const RELAY_LOCK_ID: i64 = 0x6563_686f_636c_6b73;
+
+/// A click as published to the clicks topic.
+pub struct ClickEvent {
+    slug: String,
+    payload: String,
+    headers: Vec<(String, String)>,
+    clicked_at: DateTime<Utc>,
+}
+
+impl ClickEvent {
+    /// A click on `slug` now, carrying the trace context of the current request.
+    pub fn new(slug: String) -> Self {
+        let clicked_at = Utc::now();
+        Self {
+            payload: format!(r#"{{"slug": "{}", "timestamp": "{}"}}"#, slug, clicked_at),
+            headers: TraceContext::current()
+                .map(|context| context.headers())
+                .into_iter()
+                .flatten()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            slug,
+            clicked_at,
+        }
+    }
+
+    fn from_model(model: click_outbox::Model) -> Self {
+        let headers = match model.headers {
+            Json::Object(headers) => headers
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, value.as_str()?.to_string())))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            slug: model.slug,
+            payload: model.payload,
+            headers,
+            clicked_at: model.clicked_at.into(),
+        }
+    }
+
+    /// Stamped with the time of the click, which analytics buckets it by even when it is
+    /// delivered late.
+    fn record<'a>(&'a self, topic: &'a str) -> FutureRecord<'a, str, str> {
+        let headers = self
+            .headers
+            .iter()
+            .fold(OwnedHeaders::new(), |headers, (key, value)| {
+                headers.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            });
+        FutureRecord::to(topic)
+            .payload(self.payload.as_str())
+            .key(self.slug.as_str())
+            .headers(headers)
+            .timestamp(self.clicked_at.timestamp_millis())
+    }
+}
+
+/// Click events Kafka did not take are kept in the `click_outbox` table until
+/// [`ClickOutbox::relay`] delivers them.
+#[derive(Clone)]
+pub struct ClickOutbox {
+    db: DbPool,
+    producer: FutureProducer,
+    config: KafkaConfig,
+    backlog: Arc<Mutex<Backlog>>,
+}
+
+impl ClickOutbox {
+    pub fn new(db: DbPool, producer: FutureProducer, config: &KafkaConfig) -> Self {
+        Self {
+            db,
+            producer,
+            config: config.clone(),
+            backlog: Arc::new(Mutex::new(Backlog::default())),
+        }
+    }
+
+    /// Hands `event` to Kafka without waiting for the acknowledgement. It is queued if Kafka
+    /// fails to deliver it, and straight away while earlier events are queued, so that it
+    /// is not delivered ahead of them.
+    pub fn publish(&self, event: ClickEvent) {
+        let outbox = self.clone();
+        if self.update_backlog(|backlog| backlog.queued) > 0 {
+            tokio::spawn(async move { outbox.enqueue(event).await }.in_current_span());
+            return;
+        }
+
+        let delivery = self.send(&event);
+        tokio::spawn(
+            async move {
+                match delivery.await {
+                    Ok(()) => {
+                        info!("Published click event for `{}` to Kafka", event.slug);
+                        counter!("kafka_published_total").increment(1);
+                    }
+                    Err(e) => {
+                        error!("Failed to send Kafka message, queueing it: {:?}", e);
+                        counter!("kafka_publish_errors_total").increment(1);
+                        outbox.enqueue(event).await;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Queues `event` with the producer, resolving once Kafka acknowledges or rejects it.
+    fn send(&self, event: &ClickEvent) -> impl Future<Output = Result<(), KafkaError>> + use<> {
+        let delivery = self
+            .producer
+            .send_result(event.record(&self.config.clicks_topic))
+            .map_err(|(e, _)| e);
+        async move {
+            match delivery?.await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err((e, _))) => Err(e),
+                Err(_) => Err(KafkaError::Canceled),
+            }
+        }
+    }
+
+    async fn enqueue(&self, event: ClickEvent) {
+        if self.update_backlog(|backlog| backlog.queued) >= self.config.outbox_capacity {
+            warn!("Click outbox is full, dropping click on `{}`", event.slug);
+            counter!("click_outbox_dropped_total", "reason" => "full").increment(1);
+            return;
+        }
+
+        let row = click_outbox::ActiveModel {
+            slug: Set(event.slug.clone()),
+            payload: Set(event.payload),
+            headers: Set(Json::Object(
+                event
+                    .headers
+                    .into_iter()
+                    .map(|(key, value)| (key, Json::String(value)))
+                    .collect(),
+            )),
+            clicked_at: Set(event.clicked_at.into()),
+            ..Default::default()
+        };
+        match timed("insert_click_outbox", row.insert(&*self.db)).await {
+            Ok(_) => {
+                counter!("click_outbox_enqueued_total").increment(1);
+                self.update_backlog(Backlog::enqueued);
+            }
+            Err(e) => {
+                error!("Failed to queue click on `{}`: {:?}", event.slug, e);
+                counter!("click_outbox_dropped_total", "reason" => "database").increment(1);
+            }
+        }
+    }
+
+    /// Delivers queued events oldest first, continuing straight away while full batches go
+    /// through and otherwise waiting `kafka.outbox_relay_interval_ms` between passes.
+    pub async fn relay(self) {
+        let mut lock = RelayLock::new(self.db.clone());
+        loop {
+            match self.relay_batch(&mut lock).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => warn!("Click outbox relay failed: {:?}", e),
+            }
+            tokio::time::sleep(self.config.outbox_relay_interval()).await;
+        }
+    }
+
+    /// Returns whether a full batch was delivered, i.e. more may be waiting. Only the
+    /// instance holding the relay lock delivers, so that events leave the outbox in order.
+    async fn relay_batch(&self, lock: &mut RelayLock) -> Result<bool> {
+        let since = self.update_backlog(|backlog| backlog.enqueues);
+        let backlog = timed(
+            "count_click_outbox",
+            click_outbox::Entity::find().count(&*self.db),
+        )
+        .await?;
+        self.update_backlog(|counted| counted.counted(since, backlog));
+        if backlog == 0 || !lock.acquire().await? {
+            return Ok(false);
+        }
+
+        let events: Vec<_> = timed(
+            "load_click_outbox",
+            click_outbox::Entity::find()
+                .order_by_asc(click_outbox::Column::Id)
+                .limit(self.config.outbox_batch_size)
+                .all(&*self.db),
+        )
+        .await?
+        .into_iter()
+        .map(|model| (model.id, ClickEvent::from_model(model)))
+        .collect();
+        let delivered = deliver_in_order(&events, |event| self.send(event)).await;
+
+        if !delivered.is_empty() {
+            timed(
+                "delete_click_outbox",
+                click_outbox::Entity::delete_many()
+                    .filter(click_outbox::Column::Id.is_in(delivered.iter().copied()))
+                    .exec(&*self.db),
+            )
+            .await?;
+        }
+
+        counter!("click_outbox_relayed_total").increment(delivered.len() as u64);
+        self.update_backlog(|backlog| backlog.delivered(delivered.len() as u64));
+        Ok(delivered.len() == events.len() && events.len() as u64 == self.config.outbox_batch_size)
+    }
+
+    fn update_backlog<T>(&self, update: impl FnOnce(&mut Backlog) -> T) -> T {
+        let mut backlog = self.backlog.lock().unwrap_or_else(|e| e.into_inner());
+        let result = update(&mut backlog);
+        gauge!("click_outbox_backlog").set(backlog.queued as f64);
+        result
+    }
+}
+
+/// Events queued across all instances, as of the last count plus what this instance has
+/// queued and relayed since.
+#[derive(Default)]
+struct Backlog {
+    queued: u64,
+    /// Local enqueues so far, so that a count that may have missed one is not stored over it.
+    enqueues: u64,
+}
+
+impl Backlog {
+    fn enqueued(&mut self) {
+        self.queued += 1;
+        self.enqueues += 1;
+    }
+
+    fn delivered(&mut self, events: u64) {
+        self.queued = self.queued.saturating_sub(events);
+    }
+
+    /// Takes `count`, started when `since` events had been enqueued, unless more have been
+    /// since; the next pass counts again.
+    fn counted(&mut self, since: u64, count: u64) {
+        if self.enqueues == since {
+            self.queued = count;
+        }
+    }
+}
+
+/// Sends `events` one at a time and returns the ids delivered. Delivery stops at the first
+/// failure, so that nothing after it has been sent and later events do not overtake it.
+async fn deliver_in_order<F, Fut, E>(events: &[(i64, ClickEvent)], mut send: F) -> Vec<i64>
+where
+    F: FnMut(&ClickEvent) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: Debug,
+{
+    let mut delivered = Vec::with_capacity(events.len());
+    for (id, event) in events {
+        if let Err(e) = send(event).await {
+            warn!("Failed to deliver a queued click: {:?}", e);
+            break;
+        }
+        delivered.push(*id);
+    }
+    delivered
+}
+
+/// Session-level advisory lock on a connection of its own, held across relay passes
+/// without keeping a transaction open. Losing the connection releases it.
+struct RelayLock {
+    db: DbPool,
+    connection: Option<PgConnection>,
+    held: bool,
+}
+
+impl RelayLock {
+    fn new(db: DbPool) -> Self {
+        Self {
+            db,
+            connection: None,
+            held: false,
+        }
+    }
+
+    /// Whether this instance holds the lock, taking it if it is free. Every call checks the
+    /// connection, so a lock lost with it is noticed before the next pass relays.
+    async fn acquire(&mut self) -> Result<bool> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => {
+                self.held = false;
+                self.db
+                    .get_postgres_connection_pool()
+                    .acquire()
+                    .await?
+                    .detach()
+            }
+        };
+        if self.held {
+            sqlx::query("SELECT 1").execute(&mut connection).await?;
+        } else {
+            self.held = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+                .bind(RELAY_LOCK_ID)
+                .fetch_one(&mut connection)
+                .await?;
+        }
+        self.connection = Some(connection);
+        Ok(self.held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::ready;
+
+    fn events(ids: impl IntoIterator<Item = i64>) -> Vec<(i64, ClickEvent)> {
+        ids.into_iter()
+            .map(|id| (id, ClickEvent::new(format!("slug{}", id))))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn delivers_oldest_first() {
+        let events = events([3, 7, 9]);
+        let mut sent = Vec::new();
+        let delivered = deliver_in_order(&events, |event| {
+            sent.push(event.slug.clone());
+            ready(Ok::<_, ()>(()))
+        })
+        .await;
+
+        assert_eq!(delivered, [3, 7, 9]);
+        assert_eq!(sent, ["slug3", "slug7", "slug9"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_failure() {
+        let events = events([1, 2, 3, 4]);
+        let mut sent = Vec::new();
+        let delivered = deliver_in_order(&events, |event| {
+            sent.push(event.slug.clone());
+            ready(if event.slug == "slug3" {
+                Err("broker down")
+            } else {
+                Ok(())
+            })
+        })
+        .await;
+
+        assert_eq!(delivered, [1, 2]);
+        // Nothing after the failed event was sent, so it cannot be overtaken.
+        assert_eq!(sent, ["slug1", "slug2", "slug3"]);
+    }
+
+    #[test]
+    fn backlog_follows_counts_and_local_changes() {
+        let mut backlog = Backlog::default();
+        backlog.counted(backlog.enqueues, 5);
+        assert_eq!(backlog.queued, 5);
+
+        backlog.enqueued();
+        backlog.delivered(4);
+        assert_eq!(backlog.queued, 2);
+
+        backlog.delivered(10);
+        assert_eq!(backlog.queued, 0);
+    }
+
+    #[test]
+    fn count_racing_an_enqueue_is_not_stored() {
+        let mut backlog = Backlog::default();
+        let since = backlog.enqueues;
+        // Queued while the count was running, which may not have seen the row.
+        backlog.enqueued();
+        backlog.counted(since, 0);
+        assert_eq!(backlog.queued, 1);
+
+        backlog.counted(backlog.enqueues, 3);
+        assert_eq!(backlog.queued, 3);
+    }
+}
@@ -0,0 +1,164 @@
+use metrics::counter;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// Deduplicates concurrent work per key: the first caller runs it and everyone who arrives
+/// meanwhile awaits the same result.
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<String, watch::Receiver<Option<T>>>>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: &str, work: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let mut work = Some(work);
+        loop {
+            let flight = {
+                let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+                match in_flight.get(key) {
+                    Some(receiver) => Err(receiver.clone()),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        in_flight.insert(key.to_string(), receiver);
+                        Ok(sender)
+                    }
+                }
+            };
+
+            match (flight, work.take()) {
+                (Ok(sender), Some(work)) => {
+                    let _landing = Landing { flights: self, key };
+                    let value = work().await;
+                    sender.send_replace(Some(value.clone()));
+                    return value;
+                }
+                (Err(mut receiver), unused) => {
+                    work = unused;
+                    counter!("single_flight_coalesced_total").increment(1);
+                    let value = receiver
+                        .wait_for(Option::is_some)
+                        .await
+                        .ok()
+                        .and_then(|value| value.clone());
+                    // Without a value the leader was cancelled; try to take over.
+                    if let Some(value) = value {
+                        return value;
+                    }
+                }
+                (Ok(_), None) => unreachable!("the work only runs once, after which we return"),
+            }
+        }
+    }
+}
+
+/// Ends the flight when the leader finishes or is cancelled.
+struct Landing<'a, T> {
+    flights: &'a SingleFlight<T>,
+    key: &'a str,
+}
+
+impl<T> Drop for Landing<'_, T> {
+    fn drop(&mut self) {
+        let mut in_flight = self
+            .flights
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_run() {
+        let flights = Arc::new(SingleFlight::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+
+        let callers: Vec<_> = (0..8)
+            .map(|_| {
+                let (flights, runs, release) = (flights.clone(), runs.clone(), release.clone());
+                tokio::spawn(async move {
+                    flights
+                        .run("slug", || async {
+                            runs.fetch_add(1, Ordering::SeqCst);
+                            release.notified().await;
+                            42
+                        })
+                        .await
+                })
+            })
+            .collect();
+        while runs.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..8 {
+            tokio::task::yield_now().await;
+        }
+        release.notify_one();
+
+        for caller in callers {
+            assert_eq!(caller.await.unwrap(), 42);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_and_later_calls_run_separately() {
+        let flights = SingleFlight::new();
+
+        assert_eq!(flights.run("a", || async { 1 }).await, 1);
+        assert_eq!(flights.run("b", || async { 2 }).await, 2);
+        // The first flight landed, so its result is not reused.
+        assert_eq!(flights.run("a", || async { 3 }).await, 3);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_follower_takes_over_from_a_cancelled_leader() {
+        let flights = Arc::new(SingleFlight::new());
+        let started = Arc::new(Notify::new());
+
+        let leader = tokio::spawn({
+            let (flights, started) = (flights.clone(), started.clone());
+            async move {
+                flights
+                    .run("slug", || async {
+                        started.notify_one();
+                        std::future::pending::<i32>().await
+                    })
+                    .await
+            }
+        });
+        started.notified().await;
+
+        let follower = tokio::spawn({
+            let flights = flights.clone();
+            async move { flights.run("slug", || async { 7 }).await }
+        });
+        for _ in 0..8 {
+            tokio::task::yield_now().await;
+        }
+        leader.abort();
+
+        assert_eq!(follower.await.unwrap(), 7);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{Context, Result};
+use entity::url;
+use metrics::counter;
+use redis::cluster_routing::get_slot;
+use redis::{ExistenceCheck, Pipeline, SetExpiry, SetOptions};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, QuerySelect};
+use shared::cache::{self, slug_key, CacheRecord};
+use shared::config::Config;
+use shared::connection::{Databases, RedisPool};
+use shared::prometheus::timed;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tracing::info;
+
+/// Loads the most clicked and the most recently created links into Redis, so that a flushed
+/// cache or a fresh deployment does not send all traffic to Postgres. Reads are paced to
+/// `cache.warm_up_rows_per_sec` and go to the replica; each batch is checked against the
+/// primary once written.
+pub async fn warm_up(db: &Databases, redis: &RedisPool, config: &Config) -> Result<u64> {
+    let started = Instant::now();
+    let cache = &config.cache;
+    let batch_size = cache.warm_up_batch_size.max(1);
+    let pace =
+        Duration::from_secs_f64(batch_size as f64 / cache.warm_up_rows_per_sec.max(1) as f64);
+    let mut interval = tokio::time::interval(pace);
+
+    let mut written = 0;
+    for (order, limit) in [
+        (url::Column::Clicks, cache.warm_up_most_clicked),
+        (url::Column::Id, cache.warm_up_most_recent),
+    ] {
+        let mut offset = 0;
+        while offset < limit {
+            interval.tick().await;
+            let rows = timed(
+                "warm_up_urls",
+                url::Entity::find()
+                    .select_only()
+                    .columns([url::Column::Shortened, url::Column::Original])
+                    .order_by_desc(order)
+                    .order_by_desc(url::Column::Id)
+                    .offset(offset)
+                    .limit(batch_size.min(limit - offset))
+                    .into_tuple::<(String, String)>()
+                    .all(&*db.replica),
+            )
+            .await
+            .context("Failed to load links to warm up")?;
+            if rows.is_empty() {
+                break;
+            }
+            offset += rows.len() as u64;
+            written += write_batch(redis, &rows, config.redis.cache_ttl_secs).await?;
+            correct_batch(
+                &db.primary,
+                redis,
+                &cache.invalidation_channel,
+                &rows,
+                config.redis.cache_ttl_secs,
+            )
+            .await?;
+        }
+    }
+
+    info!(
+        "Warmed up {} cache entries in {:?}",
+        written,
+        started.elapsed()
+    );
+    Ok(written)
+}
+
+/// Writes the batch as one pipeline (one per slot on a cluster, sent concurrently), leaving
+/// entries that already exist alone so that a concurrent update of a link is never
+/// overwritten with the value read earlier.
+async fn write_batch(redis: &RedisPool, rows: &[(String, String)], ttl_secs: u64) -> Result<u64> {
+    let connection = redis.connection()?;
+    let options = SetOptions::default()
+        .conditional_set(ExistenceCheck::NX)
+        .with_expiration(SetExpiry::EX(ttl_secs));
+    let mut pipes: HashMap<u16, Pipeline> = HashMap::new();
+    for (slug, original) in rows {
+        let key = slug_key(slug);
+        let slot = if connection.is_cluster() {
+            get_slot(key.as_bytes())
+        } else {
+            0
+        };
+        pipes
+            .entry(slot)
+            .or_default()
+            .set_options(key, CacheRecord::found(original), options)
+            .ignore();
+    }
+
+    let mut writes = JoinSet::new();
+    for pipe in pipes.into_values() {
+        let mut connection = connection.clone();
+        writes.spawn(async move { pipe.query_async::<()>(&mut connection).await });
+    }
+    while let Some(result) = writes.join_next().await {
+        result?.context("Failed to write warm-up batch to Redis")?;
+    }
+
+    counter!("cache_warm_up_entries_total").increment(rows.len() as u64);
+    Ok(rows.len() as u64)
+}
+
+/// Fixes the entries of links changed or deleted since the batch was read. Their own cache
+/// update may have reached Redis before the entry did, which would then serve the old
+/// destination for the full TTL.
+async fn correct_batch(
+    db: &DatabaseConnection,
+    redis: &RedisPool,
+    channel: &str,
+    rows: &[(String, String)],
+    ttl_secs: u64,
+) -> Result<()> {
+    let current: HashMap<String, String> = timed(
+        "warm_up_verify_urls",
+        url::Entity::find()
+            .select_only()
+            .columns([url::Column::Shortened, url::Column::Original])
+            .filter(url::Column::Shortened.is_in(rows.iter().map(|(slug, _)| slug.as_str())))
+            .into_tuple::<(String, String)>()
+            .all(db),
+    )
+    .await
+    .context("Failed to verify warmed-up links")?
+    .into_iter()
+    .collect();
+
+    let mut connection = redis.connection()?;
+    for (slug, original) in rows {
+        let destination = current.get(slug);
+        if destination == Some(original) {
+            continue;
+        }
+        cache::update_slug(
+            &mut connection,
+            channel,
+            slug,
+            destination.map(|url| (url.as_str(), ttl_secs)),
+        )
+        .await
+        .with_context(|| format!("Failed to correct warm-up entry for `{}`", slug))?;
+        counter!("cache_warm_up_corrections_total").increment(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use crate::connection::RedisConnection;
+use prost::Message;
+use redis::{ErrorKind, FromRedisValue, RedisError, RedisResult, RedisWrite, ToRedisArgs, Value};
+use thiserror::Error;
+
+/// Leads every encoded [`CacheRecord`]. Entries from before records existed, still found
+/// under [`legacy_slug_key`], are a bare URL (or empty, for an unknown slug), which never
+/// starts with this byte. Fields are added to the record without changing it; it only
+/// changes if the encoding itself has to.
+const RECORD_VERSION: u8 = 1;
+
+/// What is cached for a slug, so that redirects can be served without Postgres.
+#[derive(Clone, PartialEq, Message)]
+pub struct CacheRecord {
+    /// Destination of the link; absent when the slug is known not to exist.
+    #[prost(string, optional, tag = "1")]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum CacheRecordError {
+    #[error("Unsupported cache record version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Malformed cache record: {0}")]
+    Malformed(#[from] prost::DecodeError),
+
+    #[error("Cached destination is not UTF-8")]
+    NotUtf8(#[from] std::string::FromUtf8Error),
+}
+
+impl CacheRecord {
+    pub fn found(url: &str) -> Self {
+        Self {
+            url: Some(url.to_string()),
+        }
+    }
+
+    pub fn missing() -> Self {
+        Self::default()
+    }
+
+    /// Where the link points, unless the slug does not exist.
+    pub fn destination(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.encoded_len());
+        bytes.push(RECORD_VERSION);
+        self.encode(&mut bytes)
+            .expect("a Vec grows to fit the record");
+        bytes
+    }
+
+    /// Also accepts the plain-string entries written before records existed.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CacheRecordError> {
+        match bytes.split_first() {
+            Some((&RECORD_VERSION, record)) => Ok(Self::decode(record)?),
+            Some((&version, _)) if version < b' ' => {
+                Err(CacheRecordError::UnsupportedVersion(version))
+            }
+            Some(_) => Ok(Self::found(&String::from_utf8(bytes.to_vec())?)),
+            None => Ok(Self::missing()),
+        }
+    }
+}
+
+impl ToRedisArgs for CacheRecord {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg(&self.to_bytes());
+    }
+}
+
+impl FromRedisValue for CacheRecord {
+    fn from_redis_value(value: &Value) -> RedisResult<Self> {
+        let bytes = Vec::<u8>::from_redis_value(value)?;
+        Self::from_bytes(&bytes).map_err(|e| {
+            RedisError::from((ErrorKind::TypeError, "Invalid cache record", e.to_string()))
+        })
+    }
+}
+
+/// Redis key `prefix:{slug}`. The braces are a Redis Cluster hash tag, so every key built
+/// this way for the same slug lands in the same slot and can be used together in one
+/// transaction or script.
+pub fn slug_scoped_key(prefix: &str, slug: &str) -> String {
+    format!("{}:{{{}}}", prefix, slug)
+}
+
+/// Redis key holding the [`CacheRecord`] of `slug`.
+pub fn slug_key(slug: &str) -> String {
+    slug_scoped_key("slug", slug)
+}
+
+/// Key the destination of `slug` was cached under before keys carried a hash tag. Entries
+/// there are still read, and cleared whenever the link changes, until they expire, so that an
+/// upgrade neither starts from a cold cache nor leaves instances that still use the old key
+/// serving outdated links.
+pub fn legacy_slug_key(slug: &str) -> String {
+    format!("slug:{}", slug)
+}
+
+/// Rewrites (or, with `None`, removes) the cached record of `slug`, drops its legacy entry
+/// and announces the change to every redirect instance, atomically and in one round trip. On
+/// a cluster the legacy key lives in another slot and `PUBLISH` is routed by channel name, so
+/// those follow as a separate pipeline.
+pub async fn update_slug(
+    connection: &mut RedisConnection,
+    channel: &str,
+    slug: &str,
+    destination: Option<(&str, u64)>,
+) -> RedisResult<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    match destination {
+        Some((url, ttl_secs)) => pipe
+            .set_ex(slug_key(slug), CacheRecord::found(url), ttl_secs)
+            .ignore(),
+        None => pipe.del(slug_key(slug)).ignore(),
+    };
+    if connection.is_cluster() {
+        pipe.query_async::<()>(connection).await?;
+        pipe = redis::pipe();
+    }
+    pipe.del(legacy_slug_key(slug))
+        .ignore()
+        .publish(channel, slug)
+        .ignore()
+        .query_async(connection)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_records() {
+        let record = CacheRecord::found("https://example.com/a");
+
+        let decoded = CacheRecord::from_bytes(&record.to_bytes()).unwrap();
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.destination(), Some("https://example.com/a"));
+        assert_eq!(
+            CacheRecord::from_bytes(&CacheRecord::missing().to_bytes()).unwrap(),
+            CacheRecord::missing()
+        );
+    }
+
+    #[test]
+    fn reads_legacy_plain_string_entries() {
+        let found = CacheRecord::from_bytes(b"https://example.com/a").unwrap();
+        assert_eq!(found.destination(), Some("https://example.com/a"));
+
+        assert_eq!(
+            CacheRecord::from_bytes(b"").unwrap(),
+            CacheRecord::missing()
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(matches!(
+            CacheRecord::from_bytes(&[2, 0]),
+            Err(CacheRecordError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn keys_share_a_hash_tag_per_slug() {
+        assert_eq!(slug_key("abc"), "slug:{abc}");
+        assert_eq!(legacy_slug_key("abc"), "slug:abc");
+    }
+}
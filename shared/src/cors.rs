@@ -0,0 +1,120 @@
+use crate::trace_context::{REQUEST_ID, TRACEPARENT};
+use http::header::{AUTHORIZATION, CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use http::{HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+const ALLOWED_HEADERS: [HeaderName; 5] = [
+    AUTHORIZATION,
+    CONTENT_TYPE,
+    HeaderName::from_static("idempotency-key"),
+    REQUEST_ID,
+    TRACEPARENT,
+];
+
+const EXPOSED_HEADERS: [HeaderName; 7] = [
+    LOCATION,
+    RETRY_AFTER,
+    REQUEST_ID,
+    TRACEPARENT,
+    HeaderName::from_static("ratelimit-limit"),
+    HeaderName::from_static("ratelimit-remaining"),
+    HeaderName::from_static("ratelimit-reset"),
+];
+
+const GRPC_WEB_ALLOWED_HEADERS: [HeaderName; 3] = [
+    HeaderName::from_static("x-grpc-web"),
+    HeaderName::from_static("x-user-agent"),
+    HeaderName::from_static("grpc-timeout"),
+];
+
+const GRPC_WEB_EXPOSED_HEADERS: [HeaderName; 3] = [
+    HeaderName::from_static("grpc-status"),
+    HeaderName::from_static("grpc-message"),
+    HeaderName::from_static("grpc-status-details-bin"),
+];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to call the APIs, or `["*"]`. No origins are allowed by default.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PATCH", "DELETE"]
+                .map(str::to_string)
+                .to_vec(),
+            allow_credentials: false,
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    pub fn validate(&self, errors: &mut Vec<String>) {
+        for origin in &self.allowed_origins {
+            if HeaderValue::from_str(origin).is_err() {
+                errors.push(format!("cors.allowed_origins: invalid origin `{}`", origin));
+            }
+        }
+        for method in &self.allowed_methods {
+            if Method::from_bytes(method.to_uppercase().as_bytes()).is_err() {
+                errors.push(format!("cors.allowed_methods: invalid method `{}`", method));
+            }
+        }
+        if self.allow_credentials && self.allows_any_origin() {
+            errors.push("cors.allow_credentials cannot be combined with a `*` origin".to_string());
+        }
+    }
+
+    /// CORS layer for the HTTP APIs.
+    /// Entries rejected by [`CorsConfig::validate`] are skipped.
+    pub fn layer(&self) -> CorsLayer {
+        let origin = match self.allows_any_origin() {
+            true => AllowOrigin::any(),
+            false => AllowOrigin::list(
+                self.allowed_origins
+                    .iter()
+                    .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+            ),
+        };
+        let methods = self
+            .allowed_methods
+            .iter()
+            .filter_map(|method| Method::from_bytes(method.to_uppercase().as_bytes()).ok())
+            .collect::<Vec<_>>();
+
+        CorsLayer::new()
+            .allow_origin(origin)
+            .allow_methods(methods)
+            .allow_headers(ALLOWED_HEADERS)
+            .expose_headers(EXPOSED_HEADERS)
+            .allow_credentials(self.allow_credentials)
+            .max_age(Duration::from_secs(self.max_age_secs))
+    }
+
+    /// CORS layer for gRPC-Web, which always uses `POST` and reports status in headers.
+    pub fn grpc_web_layer(&self) -> CorsLayer {
+        let allowed_headers = ALLOWED_HEADERS
+            .into_iter()
+            .chain(GRPC_WEB_ALLOWED_HEADERS)
+            .collect::<Vec<_>>();
+
+        self.layer()
+            .allow_methods([Method::POST, Method::OPTIONS])
+            .allow_headers(allowed_headers)
+            .expose_headers(GRPC_WEB_EXPOSED_HEADERS)
+    }
+}
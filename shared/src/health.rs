@@ -0,0 +1,202 @@
+use crate::connection::{DbPool, RedisPool};
+use crate::shutdown::Shutdown;
+use anyhow::{anyhow, Context, Result};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::get;
+use axum::Router;
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+type CheckFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type CheckFn = Arc<dyn Fn() -> CheckFuture + Send + Sync>;
+
+/// Dependencies that must be reachable before a service accepts traffic, plus optional ones
+/// whose failure only degrades it.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    checks: Vec<(&'static str, CheckFn, bool)>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check<F, Fut>(self, name: &'static str, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.push(name, check, true)
+    }
+
+    /// Reported alongside the other checks, but never makes the service unready.
+    pub fn optional<F, Fut>(self, name: &'static str, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.push(name, check, false)
+    }
+
+    fn push<F, Fut>(mut self, name: &'static str, check: F, required: bool) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.checks.push((
+            name,
+            Arc::new(move || Box::pin(check()) as CheckFuture),
+            required,
+        ));
+        self
+    }
+
+    /// Fails once shutdown has been requested, so that load balancers stop sending traffic to
+    /// an instance that is draining.
+    pub fn draining(self, shutdown: &Shutdown) -> Self {
+        let shutdown = shutdown.clone();
+        self.check("shutdown", move || {
+            let requested = shutdown.is_requested();
+            async move {
+                match requested {
+                    true => Err(anyhow!("shutting down")),
+                    false => Ok(()),
+                }
+            }
+        })
+    }
+
+    pub fn postgres(self, db: DbPool) -> Self {
+        self.check("postgres", move || {
+            let db = db.clone();
+            async move { db.ping().await.context("Postgres ping failed") }
+        })
+    }
+
+    /// Lookups fall back to the primary while the replica is down.
+    pub fn postgres_replica(self, db: DbPool) -> Self {
+        self.optional("postgres_replica", move || {
+            let db = db.clone();
+            async move { db.ping().await.context("Postgres replica ping failed") }
+        })
+    }
+
+    pub fn redis(self, redis: RedisPool) -> Self {
+        self.check("redis", redis_ping(redis))
+    }
+
+    /// For services that only use Redis as a cache and keep serving without it.
+    pub fn redis_cache(self, redis: RedisPool) -> Self {
+        self.optional("redis", redis_ping(redis))
+    }
+
+    /// Runs every check concurrently, each bounded by a short timeout.
+    pub async fn run(&self) -> (bool, Map<String, Value>) {
+        let mut tasks = tokio::task::JoinSet::new();
+        for (name, check, required) in &self.checks {
+            let (name, check, required) = (*name, check.clone(), *required);
+            tasks.spawn(async move {
+                let result = match tokio::time::timeout(CHECK_TIMEOUT, check()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(anyhow!("timed out after {:?}", CHECK_TIMEOUT)),
+                };
+                (name, required, result)
+            });
+        }
+
+        let mut ready = true;
+        let mut results = Map::new();
+        while let Some(joined) = tasks.join_next().await {
+            let (name, required, result) = match joined {
+                Ok(outcome) => outcome,
+                Err(e) => ("unknown", true, Err(e.into())),
+            };
+            ready &= result.is_ok() || !required;
+            let status = match result {
+                Ok(()) => json!("ok"),
+                Err(e) => json!(format!("{:#}", e)),
+            };
+            results.insert(name.to_string(), status);
+        }
+        (ready, results)
+    }
+}
+
+fn redis_ping(redis: RedisPool) -> impl Fn() -> CheckFuture + Send + Sync + 'static {
+    move || {
+        let redis = redis.clone();
+        Box::pin(async move {
+            let mut connection = redis.connection()?;
+            redis::cmd("PING")
+                .query_async::<()>(&mut connection)
+                .await
+                .context("Redis ping failed")
+        })
+    }
+}
+
+/// `GET /healthz` (the process is up) and `GET /readyz` (dependencies are reachable).
+pub fn router(readiness: Readiness) -> Router {
+    Router::new()
+        .route(
+            "/healthz",
+            get(|| async { Json(json!({ "status": "ok" })) }),
+        )
+        .route(
+            "/readyz",
+            get(move || {
+                let readiness = readiness.clone();
+                async move { ready(&readiness).await }
+            }),
+        )
+}
+
+async fn ready(readiness: &Readiness) -> Response {
+    let (ready, checks) = readiness.run().await;
+    let degraded = checks.values().any(|status| status != "ok");
+    let (status, label) = match (ready, degraded) {
+        (true, false) => (StatusCode::OK, "ok"),
+        (true, true) => (StatusCode::OK, "degraded"),
+        (false, _) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    };
+    (status, Json(json!({ "status": label, "checks": checks }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn optional_checks_only_degrade() {
+        let readiness = Readiness::new()
+            .check("required", || async { Ok(()) })
+            .optional("optional", || async { Err(anyhow!("down")) });
+
+        let (ready, checks) = readiness.run().await;
+        assert!(ready);
+        assert_eq!(checks["optional"], "down");
+
+        let readiness = readiness.check("failing", || async { Err(anyhow!("down")) });
+        assert!(!readiness.run().await.0);
+    }
+
+    #[tokio::test]
+    async fn unready_once_shutdown_is_requested() {
+        let shutdown = Shutdown::install(Duration::from_secs(1));
+        let readiness = Readiness::new().draining(&shutdown);
+        assert!(readiness.run().await.0);
+
+        shutdown.trigger();
+
+        let (ready, checks) = readiness.run().await;
+        assert!(!ready);
+        assert_eq!(checks["shutdown"], "shutting down");
+    }
+}
@@ -0,0 +1,245 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const USER_ID_METADATA: &str = "x-user-id";
+pub const ISSUER_METADATA: &str = "x-auth-issuer";
+pub const TIMESTAMP_METADATA: &str = "x-identity-timestamp";
+pub const SIGNATURE_METADATA: &str = "x-identity-signature";
+
+/// Every metadata entry that carries a forwarded identity.
+pub const IDENTITY_METADATA: [&str; 4] = [
+    USER_ID_METADATA,
+    ISSUER_METADATA,
+    TIMESTAMP_METADATA,
+    SIGNATURE_METADATA,
+];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IdentityConfig {
+    /// Shared by api_gateway and the services behind it. Without it the gateway forwards no
+    /// identity and the services treat every caller as anonymous.
+    pub signing_secret: Option<String>,
+    /// How long a signed identity is accepted, bounding the replay of a captured one.
+    pub max_age_secs: u64,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            signing_secret: None,
+            max_age_secs: 300,
+        }
+    }
+}
+
+/// Caller identity established by api_gateway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardedIdentity {
+    pub user_id: String,
+    pub issuer: Option<String>,
+}
+
+/// Signs the identity api_gateway forwards and checks it in the services behind the gateway,
+/// so that a client calling a service directly cannot claim to be another user.
+#[derive(Clone)]
+pub struct IdentitySigner {
+    secret: Option<Vec<u8>>,
+    max_age: Duration,
+}
+
+impl IdentitySigner {
+    pub fn new(config: &IdentityConfig) -> Self {
+        Self {
+            secret: config
+                .signing_secret
+                .as_ref()
+                .map(|secret| secret.as_bytes().to_vec()),
+            max_age: Duration::from_secs(config.max_age_secs),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Metadata entries to attach to an outgoing request, or none without a secret.
+    pub fn sign(&self, identity: &ForwardedIdentity) -> Vec<(&'static str, String)> {
+        let Some(mac) = self.mac() else {
+            return Vec::new();
+        };
+        let timestamp = unix_time().as_secs().to_string();
+        let signature = signature(mac, &timestamp, identity);
+
+        let mut metadata = vec![
+            (USER_ID_METADATA, identity.user_id.clone()),
+            (TIMESTAMP_METADATA, timestamp),
+            (SIGNATURE_METADATA, signature),
+        ];
+        if let Some(issuer) = &identity.issuer {
+            metadata.push((ISSUER_METADATA, issuer.clone()));
+        }
+        metadata
+    }
+
+    /// The identity in `get`'s metadata if it was signed with the shared secret recently
+    /// enough; anything else, including every unsigned identity, is ignored.
+    pub fn verify<'a>(&self, get: impl Fn(&str) -> Option<&'a str>) -> Option<ForwardedIdentity> {
+        let mac = self.mac()?;
+        let identity = ForwardedIdentity {
+            user_id: get(USER_ID_METADATA)?.to_string(),
+            issuer: get(ISSUER_METADATA).map(str::to_string),
+        };
+        let timestamp = get(TIMESTAMP_METADATA)?;
+        let signed_at = Duration::from_secs(timestamp.parse().ok()?);
+        if unix_time().abs_diff(signed_at) > self.max_age {
+            return None;
+        }
+
+        let signature = hex::decode(get(SIGNATURE_METADATA)?).ok()?;
+        sign_message(mac, timestamp, &identity)
+            .verify_slice(&signature)
+            .ok()?;
+        Some(identity)
+    }
+
+    fn mac(&self) -> Option<Hmac<Sha256>> {
+        let secret = self.secret.as_ref()?;
+        Some(Hmac::new_from_slice(secret).expect("HMAC accepts keys of any length"))
+    }
+}
+
+/// Drops identity metadata from gRPC-Web requests. Browsers call the services directly over
+/// gRPC-Web rather than through api_gateway, so any identity such a request carries was not
+/// forwarded by the gateway, even when it holds a replayed signature.
+pub fn strip_from_grpc_web<B>(mut request: http::Request<B>) -> http::Request<B> {
+    let is_grpc_web = request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/grpc-web"));
+    if is_grpc_web {
+        for name in IDENTITY_METADATA {
+            request.headers_mut().remove(name);
+        }
+    }
+    request
+}
+
+fn signature(mac: Hmac<Sha256>, timestamp: &str, identity: &ForwardedIdentity) -> String {
+    hex::encode(
+        sign_message(mac, timestamp, identity)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+fn sign_message(
+    mut mac: Hmac<Sha256>,
+    timestamp: &str,
+    identity: &ForwardedIdentity,
+) -> Hmac<Sha256> {
+    let issuer = identity.issuer.as_deref().unwrap_or_default();
+    mac.update(format!("{}\n{}\n{}", timestamp, identity.user_id, issuer).as_bytes());
+    mac
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn signer(secret: Option<&str>) -> IdentitySigner {
+        IdentitySigner::new(&IdentityConfig {
+            signing_secret: secret.map(str::to_string),
+            ..IdentityConfig::default()
+        })
+    }
+
+    fn identity() -> ForwardedIdentity {
+        ForwardedIdentity {
+            user_id: "alice".to_string(),
+            issuer: Some("https://issuer.example".to_string()),
+        }
+    }
+
+    fn verify(
+        signer: &IdentitySigner,
+        metadata: &HashMap<&str, String>,
+    ) -> Option<ForwardedIdentity> {
+        signer.verify(|name| metadata.get(name).map(String::as_str))
+    }
+
+    #[test]
+    fn accepts_identity_signed_with_the_shared_secret() {
+        let signer = signer(Some("0123456789abcdef0123456789abcdef"));
+        let metadata = signer.sign(&identity()).into_iter().collect();
+
+        assert_eq!(verify(&signer, &metadata), Some(identity()));
+    }
+
+    #[test]
+    fn rejects_tampered_unsigned_or_foreign_identities() {
+        let signer = signer(Some("0123456789abcdef0123456789abcdef"));
+        let mut metadata: HashMap<_, _> = signer.sign(&identity()).into_iter().collect();
+
+        metadata.insert(USER_ID_METADATA, "mallory".to_string());
+        assert_eq!(verify(&signer, &metadata), None);
+
+        metadata.remove(SIGNATURE_METADATA);
+        assert_eq!(verify(&signer, &metadata), None);
+
+        let other = self::signer(Some("fedcba9876543210fedcba9876543210"));
+        let metadata = other.sign(&identity()).into_iter().collect();
+        assert_eq!(verify(&signer, &metadata), None);
+    }
+
+    #[test]
+    fn rejects_expired_signatures() {
+        let signer = signer(Some("0123456789abcdef0123456789abcdef"));
+        let mut metadata: HashMap<_, _> = signer.sign(&identity()).into_iter().collect();
+        let stale = (unix_time() - Duration::from_secs(3_600))
+            .as_secs()
+            .to_string();
+        let mac = signer.mac().unwrap();
+        metadata.insert(SIGNATURE_METADATA, signature(mac, &stale, &identity()));
+        metadata.insert(TIMESTAMP_METADATA, stale);
+
+        assert_eq!(verify(&signer, &metadata), None);
+    }
+
+    #[test]
+    fn strips_identity_from_grpc_web_requests_only() {
+        let request = |content_type| {
+            http::Request::builder()
+                .header(http::header::CONTENT_TYPE, content_type)
+                .header(USER_ID_METADATA, "alice")
+                .header(SIGNATURE_METADATA, "00")
+                .body(())
+                .unwrap()
+        };
+
+        let stripped = strip_from_grpc_web(request("application/grpc-web+proto"));
+        assert!(stripped.headers().get(USER_ID_METADATA).is_none());
+        assert!(stripped.headers().get(SIGNATURE_METADATA).is_none());
+
+        let kept = strip_from_grpc_web(request("application/grpc"));
+        assert_eq!(kept.headers()[USER_ID_METADATA], "alice");
+    }
+
+    #[test]
+    fn trusts_nothing_without_a_secret() {
+        let metadata = HashMap::from([(USER_ID_METADATA, "alice".to_string())]);
+
+        assert!(signer(None).sign(&identity()).is_empty());
+        assert_eq!(verify(&signer(None), &metadata), None);
+    }
+}
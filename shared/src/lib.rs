@@ -1,5 +1,16 @@
+pub mod cache;
+pub mod config;
 pub mod connection;
+pub mod cors;
+pub mod health;
+pub mod identity;
 pub mod prelude;
+pub mod prometheus;
+pub mod rate_limit;
+pub mod shutdown;
+pub mod telemetry;
+pub mod trace_context;
+pub mod validation;
 
 pub use connection::connect_db;
 pub use connection::DbPool;
@@ -0,0 +1,64 @@
+use crate::shutdown::Shutdown;
+use anyhow::{Context, Result};
+use axum::routing::get;
+use axum::Router;
+use metrics::histogram;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+const DURATION_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Installs the global Prometheus recorder used by the `metrics` macros.
+pub fn install() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".into()), &DURATION_BUCKETS)
+        .context("Invalid histogram buckets")?
+        .install_recorder()
+        .context("Failed to install Prometheus recorder")?;
+
+    // Histograms are only drained into their buckets during upkeep.
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    Ok(handle)
+}
+
+/// `GET /metrics` in the Prometheus text format, to pass to [`serve`] along with any other
+/// admin routes.
+pub fn router(handle: PrometheusHandle) -> Router {
+    Router::new().route("/metrics", get(move || async move { handle.render() }))
+}
+
+/// Serves `/metrics` (and any other admin routes in `app`) on `server.metrics_addr`, a
+/// listener of its own so that metrics are not exposed to public traffic.
+pub async fn serve(addr: SocketAddr, app: Router, shutdown: &Shutdown) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics server to {}", addr))?;
+    info!("📈 Metrics listening on {}", addr);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.requested())
+        .await
+        .context("Metrics server error")
+}
+
+/// Records the duration of a database query as `db_query_duration_seconds{query}`.
+pub async fn timed<F: Future>(query: &'static str, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    histogram!("db_query_duration_seconds", "query" => query).record(started.elapsed());
+    output
+}
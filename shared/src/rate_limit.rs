@@ -0,0 +1,564 @@
+use crate::connection::RedisPool;
+use anyhow::{bail, Context, Result};
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use redis::Script;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Token bucket kept in a Redis hash so that every instance shares the same budget.
+/// Uses the server clock to avoid skew between instances.
+static TOKEN_BUCKET: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+        local capacity = tonumber(ARGV[1])
+        local rate = tonumber(ARGV[2])
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+        local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+        local tokens = tonumber(state[1]) or capacity
+        local ts = tonumber(state[2]) or now
+        tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate)
+        local allowed = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+            allowed = 1
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+        redis.call('PEXPIRE', KEYS[1], math.ceil((capacity - tokens) / rate) + 1000)
+        return { allowed, tostring(tokens) }
+        "#,
+    )
+});
+
+/// Gives back a token taken by [`TOKEN_BUCKET`], up to the capacity. A bucket that has
+/// expired in the meantime is full already.
+static REFUND_TOKEN: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+        local tokens = tonumber(redis.call('HGET', KEYS[1], 'tokens'))
+        if tokens then
+            local capacity = tonumber(ARGV[1])
+            redis.call('HSET', KEYS[1], 'tokens', tostring(math.min(capacity, tokens + 1)))
+        end
+        return 0
+        "#,
+    )
+});
+
+const LOCAL_BUCKET_LIMIT: usize = 10_000;
+
+/// Allows `capacity` requests per `period`, refilled continuously.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RateLimitRule {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl RateLimitRule {
+    /// Tokens refilled per millisecond.
+    fn rate(&self) -> f64 {
+        self.capacity as f64 / self.period.as_millis().max(1) as f64
+    }
+}
+
+/// Parses `<count>/<s|m|h>`, e.g. `60/m`. A bare count is per minute.
+impl FromStr for RateLimitRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (count, unit) = s.trim().split_once('/').unwrap_or((s.trim(), "m"));
+        let capacity: u32 = count
+            .parse()
+            .with_context(|| format!("Invalid rate limit count in `{}`", s))?;
+        let period = match unit {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => bail!("Invalid rate limit period in `{}` (expected s, m or h)", s),
+        };
+        if capacity == 0 {
+            bail!("Rate limit `{}` must allow at least one request", s);
+        }
+
+        Ok(Self { capacity, period })
+    }
+}
+
+impl TryFrom<String> for RateLimitRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for RateLimitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.period.as_secs() {
+            1 => "s",
+            3600 => "h",
+            _ => "m",
+        };
+        write!(f, "{}/{}", self.capacity, unit)
+    }
+}
+
+impl From<RateLimitRule> for String {
+    fn from(rule: RateLimitRule) -> Self {
+        rule.to_string()
+    }
+}
+
+/// Limits are written as `60/m`; setting one to `"off"` disables it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    #[serde(deserialize_with = "rule_or_off")]
+    pub per_ip: Option<RateLimitRule>,
+    #[serde(deserialize_with = "rule_or_off")]
+    pub per_key: Option<RateLimitRule>,
+    /// Per-route limits keyed by matched path, e.g. `"/createurl" = "60/m"`.
+    pub routes: HashMap<String, RateLimitRule>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_ip: Some(RateLimitRule {
+                capacity: 300,
+                period: Duration::from_secs(60),
+            }),
+            per_key: Some(RateLimitRule {
+                capacity: 600,
+                period: Duration::from_secs(60),
+            }),
+            routes: HashMap::new(),
+        }
+    }
+}
+
+fn rule_or_off<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<RateLimitRule>, D::Error> {
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("off") | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(de::Error::custom),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until the next request would be allowed.
+    pub retry_after: Duration,
+}
+
+impl RateLimitDecision {
+    fn from_tokens(rule: RateLimitRule, allowed: bool, tokens: f64) -> Self {
+        let rate = rule.rate();
+        Self {
+            allowed,
+            limit: rule.capacity,
+            remaining: tokens.floor().max(0.0) as u32,
+            reset: Duration::from_millis(((rule.capacity as f64 - tokens) / rate).ceil() as u64),
+            retry_after: Duration::from_millis(((1.0 - tokens).max(0.0) / rate).ceil() as u64),
+        }
+    }
+
+    /// `RateLimit-*` headers, plus `Retry-After` when the request was rejected.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            (
+                "ratelimit-reset",
+                self.reset.as_secs_f64().ceil().to_string(),
+            ),
+        ];
+        if !self.allowed {
+            headers.push((
+                "retry-after",
+                self.retry_after.as_secs_f64().ceil().to_string(),
+            ));
+        }
+        headers
+    }
+}
+
+/// Identifies the client for the per-key limit. Set as a request extension by an earlier
+/// layer, such as authentication; without it requests are only limited by IP.
+#[derive(Clone, Debug)]
+pub struct ClientKey(pub String);
+
+struct LocalBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Where a bucket's token was taken from, so that a refund goes back to the same place.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Store {
+    Redis,
+    Local,
+}
+
+pub struct RateLimiter {
+    /// Prefixes the shared buckets, so that services sharing a Redis keep separate budgets.
+    service: &'static str,
+    config: RateLimitConfig,
+    redis: Option<RedisPool>,
+    local: Mutex<HashMap<String, LocalBucket>>,
+    rejection: fn() -> Response,
+}
+
+impl RateLimiter {
+    /// Without Redis, or whenever Redis fails, buckets are kept in memory per instance.
+    pub fn new(service: &'static str, config: RateLimitConfig, redis: Option<RedisPool>) -> Self {
+        Self {
+            service,
+            config,
+            redis,
+            local: Mutex::new(HashMap::new()),
+            rejection: || (StatusCode::TOO_MANY_REQUESTS, "Too many requests").into_response(),
+        }
+    }
+
+    /// Responds to rejected requests with `rejection` instead of a plain-text 429.
+    pub fn with_rejection(mut self, rejection: fn() -> Response) -> Self {
+        self.rejection = rejection;
+        self
+    }
+
+    /// Checks every bucket that applies to a request and returns the most restrictive result,
+    /// or `None` when no limit is configured. A rejected request costs no bucket a token.
+    pub async fn check_request(
+        &self,
+        ip: IpAddr,
+        client_key: Option<&str>,
+        route: &str,
+    ) -> Option<RateLimitDecision> {
+        let client = match client_key {
+            Some(key) => format!("key:{}", key),
+            None => format!("ip:{}", ip),
+        };
+
+        let mut buckets = Vec::new();
+        if let Some(rule) = self.config.per_ip {
+            buckets.push((format!("ip:{}", ip), rule));
+        }
+        if let (Some(rule), Some(key)) = (self.config.per_key, client_key) {
+            buckets.push((format!("key:{}", key), rule));
+        }
+        if let Some(rule) = self.config.routes.get(route) {
+            buckets.push((format!("route:{}:{}", route, client), *rule));
+        }
+
+        let mut taken = Vec::with_capacity(buckets.len());
+        for (bucket, rule) in &buckets {
+            taken.push(self.take(bucket, *rule).await);
+        }
+        if taken.iter().any(|(decision, _)| !decision.allowed) {
+            for ((bucket, rule), (decision, store)) in buckets.iter().zip(&taken) {
+                if decision.allowed {
+                    self.refund(bucket, *rule, *store).await;
+                }
+            }
+        }
+
+        let mut result: Option<RateLimitDecision> = None;
+        for (decision, _) in taken {
+            let replace = match &result {
+                None => true,
+                Some(current) => {
+                    (current.allowed && !decision.allowed)
+                        || (current.allowed == decision.allowed
+                            && decision.remaining < current.remaining)
+                }
+            };
+            if replace {
+                result = Some(decision);
+            }
+        }
+        result
+    }
+
+    /// Takes a token from `bucket` if it has one.
+    async fn take(&self, bucket: &str, rule: RateLimitRule) -> (RateLimitDecision, Store) {
+        if let Some(redis) = &self.redis {
+            match self.check_redis(redis, bucket, rule).await {
+                Ok(decision) => return (decision, Store::Redis),
+                Err(e) => warn!("Rate limiter falling back to memory: {:?}", e),
+            }
+        }
+        (self.check_local(bucket, rule), Store::Local)
+    }
+
+    async fn refund(&self, bucket: &str, rule: RateLimitRule, store: Store) {
+        match (store, &self.redis) {
+            (Store::Redis, Some(redis)) => {
+                if let Err(e) = self.refund_redis(redis, bucket, rule).await {
+                    warn!("Failed to refund rate limit token: {:?}", e);
+                }
+            }
+            _ => self.refund_local(bucket, rule),
+        }
+    }
+
+    async fn check_redis(
+        &self,
+        redis: &RedisPool,
+        bucket: &str,
+        rule: RateLimitRule,
+    ) -> Result<RateLimitDecision> {
+        let mut conn = redis.connection()?;
+        let (allowed, tokens): (i64, String) = TOKEN_BUCKET
+            .key(format!("ratelimit:{}:{}", self.service, bucket))
+            .arg(rule.capacity)
+            .arg(rule.rate())
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(RateLimitDecision::from_tokens(
+            rule,
+            allowed == 1,
+            tokens.parse()?,
+        ))
+    }
+
+    async fn refund_redis(
+        &self,
+        redis: &RedisPool,
+        bucket: &str,
+        rule: RateLimitRule,
+    ) -> Result<()> {
+        let mut conn = redis.connection()?;
+        let _: i64 = REFUND_TOKEN
+            .key(format!("ratelimit:{}:{}", self.service, bucket))
+            .arg(rule.capacity)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    fn check_local(&self, bucket: &str, rule: RateLimitRule) -> RateLimitDecision {
+        let now = Instant::now();
+        let mut buckets = self.local.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= LOCAL_BUCKET_LIMIT {
+            // Idle buckets have refilled completely and can be recreated on demand.
+            buckets.retain(|_, b| now.duration_since(b.updated) < rule.period);
+        }
+
+        let entry = buckets.entry(bucket.to_string()).or_insert(LocalBucket {
+            tokens: rule.capacity as f64,
+            updated: now,
+        });
+        let elapsed = now.duration_since(entry.updated).as_millis() as f64;
+        entry.tokens = (entry.tokens + elapsed * rule.rate()).min(rule.capacity as f64);
+        entry.updated = now;
+
+        let allowed = entry.tokens >= 1.0;
+        if allowed {
+            entry.tokens -= 1.0;
+        }
+        RateLimitDecision::from_tokens(rule, allowed, entry.tokens)
+    }
+
+    fn refund_local(&self, bucket: &str, rule: RateLimitRule) {
+        let mut buckets = self.local.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = buckets.get_mut(bucket) {
+            entry.tokens = (entry.tokens + 1.0).min(rule.capacity as f64);
+        }
+    }
+}
+
+/// Middleware enforcing `limiter` on the matched route, adding `RateLimit-*` headers to
+/// every limited response.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path
+        .as_ref()
+        .map(MatchedPath::as_str)
+        .unwrap_or_else(|| request.uri().path());
+    let client_key = request
+        .extensions()
+        .get::<ClientKey>()
+        .map(|key| key.0.as_str());
+
+    let Some(decision) = limiter.check_request(addr.ip(), client_key, route).await else {
+        return next.run(request).await;
+    };
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        warn!("Rate limit exceeded for {} on `{}`", addr.ip(), route);
+        (limiter.rejection)()
+    };
+    for (name, value) in decision.headers() {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(name), value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn rule(capacity: u32, period_secs: u64) -> RateLimitRule {
+        RateLimitRule {
+            capacity,
+            period: Duration::from_secs(period_secs),
+        }
+    }
+
+    fn local_limiter(per_ip: Option<RateLimitRule>, per_key: Option<RateLimitRule>) -> RateLimiter {
+        let config = RateLimitConfig {
+            per_ip,
+            per_key,
+            routes: HashMap::new(),
+        };
+        RateLimiter::new("test", config, None)
+    }
+
+    /// Moves the last update of `bucket` into the past, as if `elapsed` had gone by.
+    fn age(limiter: &RateLimiter, bucket: &str, elapsed: Duration) {
+        let mut buckets = limiter.local.lock().unwrap();
+        let entry = buckets.get_mut(bucket).unwrap();
+        entry.updated -= elapsed;
+    }
+
+    fn tokens(limiter: &RateLimiter, bucket: &str) -> f64 {
+        limiter.local.lock().unwrap()[bucket].tokens
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!("60/m".parse::<RateLimitRule>().unwrap(), rule(60, 60));
+        assert_eq!("5/s".parse::<RateLimitRule>().unwrap(), rule(5, 1));
+        assert_eq!("100".parse::<RateLimitRule>().unwrap(), rule(100, 60));
+        assert!("0/m".parse::<RateLimitRule>().is_err());
+        assert!("10/d".parse::<RateLimitRule>().is_err());
+    }
+
+    #[test]
+    fn local_bucket_empties_and_refills() {
+        let limiter = local_limiter(None, None);
+        let rule = rule(2, 1);
+
+        assert!(limiter.check_local("b", rule).allowed);
+        assert!(limiter.check_local("b", rule).allowed);
+        let rejected = limiter.check_local("b", rule);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.remaining, 0);
+
+        // Two tokens a second: half a second brings one back.
+        age(&limiter, "b", Duration::from_millis(500));
+        let decision = limiter.check_local("b", rule);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+
+        // Refills stop at the capacity.
+        age(&limiter, "b", Duration::from_secs(60));
+        let decision = limiter.check_local("b", rule);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn retry_after_is_the_time_to_the_next_token() {
+        let decision = RateLimitDecision::from_tokens(rule(60, 60), false, 0.25);
+
+        assert_eq!(decision.limit, 60);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Duration::from_millis(750));
+        assert_eq!(decision.reset, Duration::from_millis(59_750));
+        let headers = decision.headers();
+        assert!(headers.contains(&("retry-after", "1".to_string())));
+        assert!(headers.contains(&("ratelimit-reset", "60".to_string())));
+
+        let allowed = RateLimitDecision::from_tokens(rule(60, 60), true, 59.0);
+        assert_eq!(allowed.retry_after, Duration::ZERO);
+        assert!(!allowed
+            .headers()
+            .iter()
+            .any(|(name, _)| *name == "retry-after"));
+    }
+
+    #[tokio::test]
+    async fn returns_the_most_restrictive_bucket() {
+        let limiter = local_limiter(Some(rule(10, 60)), Some(rule(3, 60)));
+
+        let decision = limiter.check_request(IP, Some("k"), "/").await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.limit, 3);
+        assert_eq!(decision.remaining, 2);
+
+        assert!(limiter.check_request(IP, None, "/").await.is_some());
+        assert!(local_limiter(None, None)
+            .check_request(IP, None, "/")
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn rejection_by_the_key_costs_the_ip_nothing() {
+        let limiter = local_limiter(Some(rule(5, 60)), Some(rule(1, 60)));
+
+        assert!(
+            limiter
+                .check_request(IP, Some("k"), "/")
+                .await
+                .unwrap()
+                .allowed
+        );
+        let decision = limiter.check_request(IP, Some("k"), "/").await.unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.limit, 1);
+
+        assert_eq!(tokens(&limiter, "ip:127.0.0.1").floor(), 4.0);
+    }
+
+    #[tokio::test]
+    async fn rejection_by_the_ip_costs_the_key_nothing() {
+        let limiter = local_limiter(Some(rule(1, 60)), Some(rule(5, 60)));
+
+        assert!(
+            limiter
+                .check_request(IP, Some("k"), "/")
+                .await
+                .unwrap()
+                .allowed
+        );
+        let decision = limiter.check_request(IP, Some("k"), "/").await.unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.limit, 1);
+
+        assert_eq!(tokens(&limiter, "key:k").floor(), 4.0);
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::Result;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Coordinates shutdown across the servers and background tasks of one binary: triggered by
+/// SIGINT/SIGTERM (or by a component that stopped on its own), after which every server stops
+/// accepting work and gets `grace_period` to drain.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    pub grace_period: Duration,
+}
+
+impl Shutdown {
+    /// Starts listening for signals.
+    pub fn install(grace_period: Duration) -> Self {
+        let (sender, receiver) = watch::channel(false);
+        let shutdown = Self {
+            sender: Arc::new(sender),
+            receiver,
+            grace_period,
+        };
+
+        let trigger = shutdown.clone();
+        tokio::spawn(async move {
+            signal().await;
+            info!(
+                "🛑 Shutdown requested, draining for up to {:?}",
+                trigger.grace_period
+            );
+            trigger.trigger();
+        });
+        shutdown
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once shutdown has been requested; suitable for `with_graceful_shutdown` and
+    /// `serve_with_shutdown`.
+    pub fn requested(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut receiver = self.receiver.clone();
+        async move {
+            // The sender lives as long as any clone of `Shutdown`, so an error cannot occur
+            // before shutdown has been requested.
+            let _ = receiver.wait_for(|requested| *requested).await;
+        }
+    }
+
+    /// Runs a server that stops accepting work on [`Shutdown::requested`], and abandons it if
+    /// draining takes longer than the grace period.
+    pub async fn drain<F>(&self, name: &str, server: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        tokio::pin!(server);
+        tokio::select! {
+            result = &mut server => {
+                self.trigger();
+                return result;
+            }
+            _ = self.requested() => {}
+        }
+
+        match tokio::time::timeout(self.grace_period, server).await {
+            Ok(result) => {
+                info!("{} drained", name);
+                result
+            }
+            Err(_) => {
+                warn!(
+                    "{} still had requests in flight after {:?}; closing them",
+                    name, self.grace_period
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
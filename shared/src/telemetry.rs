@@ -0,0 +1,353 @@
+use anyhow::{Context, Result};
+use http::HeaderMap;
+use metrics::histogram;
+use opentelemetry::metrics::Histogram;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{warn, Span};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+static HTTP_DURATION: LazyLock<Histogram<f64>> = LazyLock::new(|| {
+    global::meter("echourl")
+        .f64_histogram("http.server.request.duration")
+        .with_unit("s")
+        .with_description("Duration of HTTP requests")
+        .build()
+});
+
+static GRPC_DURATION: LazyLock<Histogram<f64>> = LazyLock::new(|| {
+    global::meter("echourl")
+        .f64_histogram("rpc.server.duration")
+        .with_unit("s")
+        .with_description("Duration of gRPC calls")
+        .build()
+});
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub service_name: String,
+    /// OTLP/gRPC collector; export is disabled when unset.
+    pub otlp_endpoint: Option<String>,
+    pub export_timeout_ms: u64,
+    pub metrics_interval_ms: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            service_name: "echourl".to_string(),
+            otlp_endpoint: None,
+            export_timeout_ms: 10_000,
+            metrics_interval_ms: 60_000,
+        }
+    }
+}
+
+/// Flushes and stops the exporters when dropped; keep it alive for the whole of `main`.
+pub struct Telemetry {
+    tracer_provider: Option<TracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(&mut self) {
+        if let Some(Err(e)) = self
+            .tracer_provider
+            .take()
+            .map(|provider| provider.shutdown())
+        {
+            warn!("Failed to flush spans: {:?}", e);
+        }
+        if let Some(Err(e)) = self
+            .meter_provider
+            .take()
+            .map(|provider| provider.shutdown())
+        {
+            warn!("Failed to flush metrics: {:?}", e);
+        }
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Installs the global subscriber: log output filtered by `RUST_LOG` (default `info`) and,
+/// when an OTLP endpoint is configured, span and metric export.
+pub fn init(config: &TelemetryConfig) -> Result<Telemetry> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+        registry
+            .try_init()
+            .context("Failed to install tracing subscriber")?;
+        return Ok(Telemetry {
+            tracer_provider: None,
+            meter_provider: None,
+        });
+    };
+
+    let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+
+    let span_exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .with_timeout(Duration::from_millis(config.export_timeout_ms))
+        .build()
+        .context("Failed to create OTLP span exporter")?;
+    let tracer_provider = TracerProvider::builder()
+        .with_batch_exporter(span_exporter, runtime::Tokio)
+        .with_resource(resource.clone())
+        .build();
+    let tracer = tracer_provider.tracer(config.service_name.clone());
+    global::set_tracer_provider(tracer_provider.clone());
+
+    let metric_exporter = MetricExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .with_timeout(Duration::from_millis(config.export_timeout_ms))
+        .build()
+        .context("Failed to create OTLP metric exporter")?;
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(
+            PeriodicReader::builder(metric_exporter, runtime::Tokio)
+                .with_interval(Duration::from_millis(config.metrics_interval_ms))
+                .build(),
+        )
+        .with_resource(resource)
+        .build();
+    global::set_meter_provider(meter_provider.clone());
+
+    registry
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .context("Failed to install tracing subscriber")?;
+
+    Ok(Telemetry {
+        tracer_provider: Some(tracer_provider),
+        meter_provider: Some(meter_provider),
+    })
+}
+
+/// `on_response` hook for `TraceLayer::new_for_http`, recorded in both OTLP and Prometheus.
+pub fn on_http_response<B>(response: &http::Response<B>, latency: Duration, _: &Span) {
+    histogram!(
+        "http_request_duration_seconds",
+        "status" => response.status().as_str().to_string(),
+    )
+    .record(latency);
+    HTTP_DURATION.record(
+        latency.as_secs_f64(),
+        &[KeyValue::new(
+            "http.response.status_code",
+            i64::from(response.status().as_u16()),
+        )],
+    );
+}
+
+/// `on_response` hook for `TraceLayer::new_for_grpc`. Only trailers-only responses carry the
+/// status in headers; streamed responses are recorded as `0` (OK).
+pub fn on_grpc_response<B>(response: &http::Response<B>, latency: Duration, _: &Span) {
+    histogram!(
+        "grpc_request_duration_seconds",
+        "status" => grpc_status(response.headers()).to_string(),
+    )
+    .record(latency);
+    GRPC_DURATION.record(
+        latency.as_secs_f64(),
+        &[KeyValue::new(
+            "rpc.grpc.status_code",
+            grpc_status(response.headers()),
+        )],
+    );
+}
+
+fn grpc_status(headers: &HeaderMap) -> i64 {
+    headers
+        .get("grpc-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use opentelemetry_proto::tonic::common::v1::any_value::Value;
+    use opentelemetry_sdk::metrics::data::{self, ResourceMetrics};
+    use opentelemetry_sdk::metrics::reader::MetricReader;
+    use opentelemetry_sdk::metrics::{
+        InstrumentKind, ManualReader, MetricResult, Pipeline, Temporality,
+    };
+    use std::sync::{Arc, Weak};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, Mutex};
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+
+    /// Held by tests that install global providers, so that they do not replace each other's.
+    static GLOBAL_PROVIDERS: Mutex<()> = Mutex::const_new(());
+
+    /// OTLP collector that hands every export request to the test.
+    struct Collector(mpsc::UnboundedSender<ExportTraceServiceRequest>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            let _ = self.0.send(request.into_inner());
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    async fn start_collector() -> (String, mpsc::UnboundedReceiver<ExportTraceServiceRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        let (exports, received) = mpsc::unbounded_channel();
+        tokio::spawn(
+            Server::builder()
+                .add_service(TraceServiceServer::new(Collector(exports)))
+                .serve_with_incoming(incoming),
+        );
+        (endpoint, received)
+    }
+
+    // The batch exporter is flushed from a blocking call, which needs a second worker.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn exports_spans_to_the_otlp_collector() {
+        let _providers = GLOBAL_PROVIDERS.lock().await;
+        let (endpoint, mut received) = start_collector().await;
+        let mut telemetry = init(&TelemetryConfig {
+            service_name: "telemetry-test".to_string(),
+            otlp_endpoint: Some(endpoint),
+            export_timeout_ms: 5_000,
+            ..TelemetryConfig::default()
+        })
+        .unwrap();
+
+        tracing::info_span!("exported_operation").in_scope(|| tracing::info!("inside the span"));
+        telemetry.shutdown();
+
+        let export = tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await
+            .expect("no spans reached the collector")
+            .unwrap();
+        let resource_spans = &export.resource_spans[0];
+        let service_name = resource_spans
+            .resource
+            .iter()
+            .flat_map(|resource| &resource.attributes)
+            .find(|attribute| attribute.key == "service.name")
+            .and_then(|attribute| attribute.value.as_ref()?.value.as_ref());
+        assert_eq!(
+            service_name,
+            Some(&Value::StringValue("telemetry-test".to_string()))
+        );
+        let span_names: Vec<_> = resource_spans
+            .scope_spans
+            .iter()
+            .flat_map(|scope| &scope.spans)
+            .map(|span| span.name.as_str())
+            .collect();
+        assert_eq!(span_names, ["exported_operation"]);
+    }
+
+    /// Gives the meter provider a reader while the test keeps a handle to collect from.
+    #[derive(Clone, Debug)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self) -> MetricResult<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> MetricResult<()> {
+            self.0.shutdown()
+        }
+
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    /// Count and attributes of every data point recorded in the histogram `name`.
+    fn histogram_points(metrics: &ResourceMetrics, name: &str) -> Vec<(u64, Vec<KeyValue>)> {
+        metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| &scope.metrics)
+            .filter(|metric| metric.name == name)
+            .filter_map(|metric| metric.data.as_any().downcast_ref::<data::Histogram<f64>>())
+            .flat_map(|histogram| &histogram.data_points)
+            .map(|point| (point.count, point.attributes.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn records_request_durations_as_otlp_metrics() {
+        let _providers = GLOBAL_PROVIDERS.blocking_lock();
+        let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        global::set_meter_provider(provider.clone());
+
+        let latency = Duration::from_millis(20);
+        let created = http::Response::builder().status(201).body(()).unwrap();
+        on_http_response(&created, latency, &Span::none());
+        on_http_response(&created, latency, &Span::none());
+        let not_found = http::Response::builder()
+            .header("grpc-status", "5")
+            .body(())
+            .unwrap();
+        on_grpc_response(&not_found, latency, &Span::none());
+
+        let mut metrics = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut metrics).unwrap();
+        assert_eq!(
+            histogram_points(&metrics, "http.server.request.duration"),
+            [(2, vec![KeyValue::new("http.response.status_code", 201)])]
+        );
+        assert_eq!(
+            histogram_points(&metrics, "rpc.server.duration"),
+            [(1, vec![KeyValue::new("rpc.grpc.status_code", 5)])]
+        );
+        provider.shutdown().unwrap();
+    }
+}
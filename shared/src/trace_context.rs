@@ -0,0 +1,220 @@
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use rand::Rng;
+use std::future::Future;
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Correlation data carried across HTTP, gRPC metadata and Kafka headers: the caller's
+/// `x-request-id` and a W3C trace context (`traceparent`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    pub request_id: String,
+    pub trace_id: String,
+    /// The caller's span, absent when this hop started the trace.
+    pub parent_id: Option<String>,
+    /// This hop's span id, sent downstream as the parent id unless spans are exported.
+    pub span_id: String,
+    pub flags: String,
+}
+
+impl TraceContext {
+    /// Starts a new trace; the request id defaults to the trace id.
+    pub fn new() -> Self {
+        let trace_id = random_hex(16);
+        Self {
+            request_id: trace_id.clone(),
+            trace_id,
+            parent_id: None,
+            span_id: random_hex(8),
+            flags: "01".to_string(),
+        }
+    }
+
+    /// Continues the trace found in a carrier, or starts a new one when it is missing or
+    /// malformed. Always opens a new span id for this hop.
+    pub fn extract<'a>(get: impl Fn(&str) -> Option<&'a str>) -> Self {
+        let mut context = get(TRACEPARENT.as_str())
+            .and_then(parse_traceparent)
+            .map(|(trace_id, parent_id, flags)| Self {
+                request_id: trace_id.clone(),
+                trace_id,
+                parent_id: Some(parent_id),
+                span_id: random_hex(8),
+                flags,
+            })
+            .unwrap_or_default();
+
+        if let Some(request_id) = get(REQUEST_ID.as_str()).filter(|id| valid_request_id(id)) {
+            context.request_id = request_id.to_string();
+        }
+        context
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self::extract(|name| headers.get(name).and_then(|value| value.to_str().ok()))
+    }
+
+    /// Points downstream services at the current exported span when there is one, so that
+    /// their spans nest under it.
+    pub fn traceparent(&self) -> String {
+        let current = Span::current().context();
+        let span = current.span();
+        let span_context = span.span_context();
+        let span_id =
+            if span_context.is_valid() && span_context.trace_id().to_string() == self.trace_id {
+                span_context.span_id().to_string()
+            } else {
+                self.span_id.clone()
+            };
+        format!("00-{}-{}-{}", self.trace_id, span_id, self.flags)
+    }
+
+    /// Makes `span` part of this trace when spans are exported. A trace started here gets this
+    /// hop's span id as its (unexported) root so that the exported trace id matches the logs.
+    pub fn link(&self, span: &Span) {
+        let parent_id = self.parent_id.as_deref().unwrap_or(&self.span_id);
+        let (Ok(trace_id), Ok(parent_id), Ok(flags)) = (
+            TraceId::from_hex(&self.trace_id),
+            SpanId::from_hex(parent_id),
+            u8::from_str_radix(&self.flags, 16),
+        ) else {
+            return;
+        };
+        let parent = SpanContext::new(
+            trace_id,
+            parent_id,
+            TraceFlags::new(flags),
+            true,
+            TraceState::default(),
+        );
+        span.set_parent(opentelemetry::Context::new().with_remote_span_context(parent));
+    }
+
+    /// Header name/value pairs to attach to outgoing requests and messages.
+    pub fn headers(&self) -> [(&'static str, String); 2] {
+        [
+            ("x-request-id", self.request_id.clone()),
+            ("traceparent", self.traceparent()),
+        ]
+    }
+
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        for (name, value) in self.headers() {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+    }
+
+    /// The context of the request being handled by the current task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Makes this context available through [`TraceContext::current`] while `future` runs.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Accepts or generates the trace context of an incoming HTTP request, exposes it to the
+/// rest of the stack and echoes it back on the response.
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let context = TraceContext::from_headers(request.headers());
+    context.inject(request.headers_mut());
+    request.extensions_mut().insert(context.clone());
+
+    let mut response = context.clone().scope(next.run(request)).await;
+    context.inject(response.headers_mut());
+    response
+}
+
+/// Span for an HTTP request that went through [`propagate`].
+pub fn http_span<B>(request: &http::Request<B>) -> Span {
+    let context = request
+        .extensions()
+        .get::<TraceContext>()
+        .cloned()
+        .unwrap_or_default();
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = %context.request_id,
+        trace_id = %context.trace_id,
+    );
+    context.link(&span);
+    span
+}
+
+/// Span for an incoming gRPC call, for use with `TraceLayer::new_for_grpc`.
+pub fn grpc_span<B>(request: &http::Request<B>) -> Span {
+    let context = TraceContext::from_headers(request.headers());
+    let span = info_span!(
+        "grpc",
+        path = %request.uri().path(),
+        request_id = %context.request_id,
+        trace_id = %context.trace_id,
+    );
+    context.link(&span);
+    span
+}
+
+fn parse_traceparent(value: &str) -> Option<(String, String, String)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+
+    let valid = is_hex(version, 2)
+        && version != "ff"
+        && (version != "00" || parts.next().is_none())
+        && is_hex(trace_id, 32)
+        && trace_id.bytes().any(|b| b != b'0')
+        && is_hex(parent_id, 16)
+        && parent_id.bytes().any(|b| b != b'0')
+        && is_hex(flags, 2);
+    valid.then(|| {
+        (
+            trace_id.to_string(),
+            parent_id.to_string(),
+            flags.to_string(),
+        )
+    })
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::rng();
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.random::<u8>()))
+        .collect()
+}
@@ -0,0 +1,53 @@
+use url::Url;
+
+const MAX_URL_LEN: usize = 2048;
+
+/// Checks that `url` is an absolute `http` or `https` URL that a redirect can point to,
+/// describing the problem otherwise.
+pub fn validate_url(url: &str) -> Result<(), String> {
+    if url.trim().is_empty() {
+        return Err("URL must not be empty".to_string());
+    }
+    if url.len() > MAX_URL_LEN {
+        return Err(format!("URL must be at most {} characters", MAX_URL_LEN));
+    }
+
+    let parsed = Url::parse(url).map_err(|e| format!("Invalid URL: {}", e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!(
+            "Invalid URL: scheme `{}` is not http or https",
+            parsed.scheme()
+        ));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err("Invalid URL: missing host".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_http_and_https_urls() {
+        assert_eq!(validate_url("https://example.com/a?b=c#d"), Ok(()));
+        assert_eq!(validate_url("http://127.0.0.1:8080"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unparseable_or_non_web_urls() {
+        for url in [
+            "",
+            "   ",
+            "example.com",
+            "https://",
+            "javascript:alert(1)",
+            "ftp://example.com/file",
+            "mailto:someone@example.com",
+        ] {
+            assert!(validate_url(url).is_err(), "accepted `{}`", url);
+        }
+        assert!(validate_url(&format!("https://example.com/{}", "a".repeat(MAX_URL_LEN))).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use entity::{stale_cache_entry, url};
+use metrics::gauge;
+use redis::RedisResult;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::sqlx::types::chrono::Utc;
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, DbErr, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set,
+};
+use shared::cache;
+use shared::config::Config;
+use shared::connection::RedisPool;
+use shared::prometheus::timed;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(5);
+const RECONCILE_BATCH_SIZE: u64 = 500;
+
+/// Mirrors link changes into Redis. Redis is only a cache, so a failed write does not fail
+/// the request: the slug is recorded in the `stale_cache_entry` table and its entry rewritten
+/// from Postgres once Redis is reachable again, by whichever instance gets there first, even
+/// if this one has since restarted.
+#[derive(Clone)]
+pub struct CacheWriter {
+    db: Arc<DatabaseConnection>,
+    redis: RedisPool,
+    ttl_secs: u64,
+    channel: String,
+}
+
+impl CacheWriter {
+    pub fn new(db: Arc<DatabaseConnection>, redis: RedisPool, config: &Config) -> Self {
+        Self {
+            db,
+            redis,
+            ttl_secs: config.redis.cache_ttl_secs,
+            channel: config.cache.invalidation_channel.clone(),
+        }
+    }
+
+    /// Points the cached entries of `slugs` at `url`, or evicts them, and invalidates the
+    /// copies held by redirect instances.
+    pub async fn update(&self, slugs: &[String], url: Option<&str>) {
+        for slug in slugs {
+            if let Err(e) = self.write(slug, url).await {
+                warn!("Failed to update cache entry for `{}`: {:?}", slug, e);
+                if let Err(e) = self.mark_stale(slug).await {
+                    error!("Failed to record stale cache entry `{}`: {:?}", slug, e);
+                }
+            }
+        }
+    }
+
+    /// Rewrites the entries that could not be updated, from the current state of Postgres.
+    pub async fn reconcile(self) {
+        let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.redis.connection().is_err() {
+                continue;
+            }
+            if let Err(e) = self.reconcile_batch().await {
+                warn!("Cache reconciliation failed: {:?}", e);
+            }
+        }
+    }
+
+    async fn reconcile_batch(&self) -> Result<(), DbErr> {
+        let stale = timed(
+            "load_stale_cache_entries",
+            stale_cache_entry::Entity::find()
+                .order_by_asc(stale_cache_entry::Column::MarkedAt)
+                .limit(RECONCILE_BATCH_SIZE)
+                .all(&*self.db),
+        )
+        .await?;
+        if stale.is_empty() {
+            gauge!("cache_stale_entries").set(0.0);
+            return Ok(());
+        }
+
+        let mut repaired = 0;
+        for entry in stale {
+            let found = timed(
+                "find_by_slug",
+                url::Entity::find()
+                    .filter(url::Column::Shortened.eq(&entry.slug))
+                    .one(&*self.db),
+            )
+            .await?;
+            if self
+                .write(&entry.slug, found.as_ref().map(|m| m.original.as_str()))
+                .await
+                .is_err()
+            {
+                // Redis is gone again; retry on the next tick.
+                break;
+            }
+            // Unless the slug went stale again meanwhile, which needs another repair.
+            stale_cache_entry::Entity::delete_many()
+                .filter(stale_cache_entry::Column::Slug.eq(&entry.slug))
+                .filter(stale_cache_entry::Column::MarkedAt.eq(entry.marked_at))
+                .exec(&*self.db)
+                .await?;
+            repaired += 1;
+        }
+
+        let remaining = stale_cache_entry::Entity::find().count(&*self.db).await?;
+        gauge!("cache_stale_entries").set(remaining as f64);
+        if repaired > 0 {
+            info!(
+                "Reconciled {} cache entries ({} remaining)",
+                repaired, remaining
+            );
+        }
+        Ok(())
+    }
+
+    async fn write(&self, slug: &str, url: Option<&str>) -> RedisResult<()> {
+        let mut connection = self.redis.connection()?;
+        cache::update_slug(
+            &mut connection,
+            &self.channel,
+            slug,
+            url.map(|url| (url, self.ttl_secs)),
+        )
+        .await
+    }
+
+    async fn mark_stale(&self, slug: &str) -> Result<(), DbErr> {
+        let entry = stale_cache_entry::ActiveModel {
+            slug: Set(slug.to_string()),
+            marked_at: Set(Utc::now().into()),
+        };
+        timed(
+            "mark_stale_cache_entry",
+            stale_cache_entry::Entity::insert(entry)
+                .on_conflict(
+                    OnConflict::column(stale_cache_entry::Column::Slug)
+                        .update_column(stale_cache_entry::Column::MarkedAt)
+                        .to_owned(),
+                )
+                .exec_without_returning(&*self.db),
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -1,23 +1,53 @@
+use crate::cache::CacheWriter;
 use anyhow::{Context, Result};
 use echourl::shorten_url_server::{ShortenUrl, ShortenUrlServer};
-use echourl::{DeleteResponse, OriginalUrl, ShortenedUrl};
+use echourl::{
+    DeleteResponse, ListUrlsRequest, ListUrlsResponse, OriginalUrl, ShortenedUrl, Slug,
+    UpdateUrlRequest,
+};
 use entity::url;
+use metrics::counter;
 use rand::distr::Alphanumeric;
 use rand::{rng, Rng};
-use redis::AsyncCommands;
-use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, Set};
-use shared::connection::{connect_db, connect_redis};
-use std::env;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, IntoActiveModel,
+    QueryFilter, QueryOrder, QuerySelect, Set,
+};
+use shared::config::{Config, ServiceDefaults};
+use shared::connection::{connect_databases, connect_redis, Databases};
+use shared::health::{self, Readiness};
+use shared::identity::{self, IdentitySigner};
+use shared::prometheus::{self, timed};
+use shared::shutdown::Shutdown;
+use shared::telemetry;
+use shared::trace_context;
+use shared::validation::validate_url;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tonic::server::NamedService;
 use tonic::transport::Server;
 use tonic::{Request, Response, Status};
-use tracing::{error, info, Level};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tonic_web::GrpcWebLayer;
+use tower::util::MapRequestLayer;
+use tower_http::trace::TraceLayer;
+use tracing::{info, warn};
+
+mod cache;
 
 mod echourl {
     tonic::include_proto!("echourl");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("echourl_descriptor");
 }
 
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 100;
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Error)]
 pub enum UrlShortenerError {
     #[error("Database error: {0}")]
@@ -29,6 +59,9 @@ pub enum UrlShortenerError {
     #[error("Failed to generate short code")]
     ShortCodeGenerationFailed,
 
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
     #[error("Internal server error: {0}")]
     InternalServerError(String),
 }
@@ -41,6 +74,7 @@ impl From<UrlShortenerError> for Status {
             UrlShortenerError::ShortCodeGenerationFailed => {
                 Status::internal("Failed to generate short code")
             }
+            UrlShortenerError::InvalidArgument(msg) => Status::invalid_argument(msg),
             UrlShortenerError::InternalServerError(msg) => Status::internal(msg),
         }
     }
@@ -61,15 +95,74 @@ fn generate_short_code(length: usize) -> Result<String, UrlShortenerError> {
     }
 }
 
-#[derive(Debug)]
+/// Returns the caller identity forwarded by api_gateway, if it carries a valid signature.
+fn caller_id<T>(signer: &IdentitySigner, request: &Request<T>) -> Option<String> {
+    let metadata = request.metadata();
+    signer
+        .verify(|name| metadata.get(name)?.to_str().ok())
+        .map(|identity| identity.user_id)
+}
+
 struct ShortenUrlService {
     db: Arc<DatabaseConnection>,
-    redis: Arc<redis::Client>,
+    /// Serves listings, which tolerate replication lag.
+    replica: Arc<DatabaseConnection>,
+    cache: CacheWriter,
+    signer: IdentitySigner,
 }
 
 impl ShortenUrlService {
-    pub fn new(db: Arc<DatabaseConnection>, redis: Arc<redis::Client>) -> Self {
-        Self { db, redis }
+    pub fn new(databases: Databases, cache: CacheWriter, signer: IdentitySigner) -> Self {
+        Self {
+            db: databases.primary,
+            replica: databases.replica,
+            cache,
+            signer,
+        }
+    }
+
+    async fn find_by_slug(&self, slug: &str) -> Result<url::Model, UrlShortenerError> {
+        timed(
+            "find_by_slug",
+            url::Entity::find()
+                .filter(url::Column::Shortened.eq(slug))
+                .one(&*self.db),
+        )
+        .await?
+        .ok_or(UrlShortenerError::NotFound)
+    }
+
+    /// Reads from the replica, or from the primary when the replica fails.
+    async fn list_urls(&self, after_id: i32, limit: u64) -> Result<Vec<url::Model>, DbErr> {
+        let query = || {
+            url::Entity::find()
+                .filter(url::Column::Id.gt(after_id))
+                .order_by_asc(url::Column::Id)
+                .limit(limit)
+        };
+        if Arc::ptr_eq(&self.replica, &self.db) {
+            return timed("list_urls", query().all(&*self.db)).await;
+        }
+        match timed("list_urls_replica", query().all(&*self.replica)).await {
+            Ok(rows) => Ok(rows),
+            Err(e) => {
+                warn!("Replica listing failed, using the primary: {:?}", e);
+                counter!("shortener_replica_errors_total").increment(1);
+                timed("list_urls", query().all(&*self.db)).await
+            }
+        }
+    }
+}
+
+impl From<url::Model> for ShortenedUrl {
+    fn from(model: url::Model) -> Self {
+        Self {
+            id: model.id,
+            original_url: model.original,
+            shortened_url: model.shortened,
+            clicks: model.clicks,
+            created_at: model.created_at.to_string(),
+        }
     }
 }
 
@@ -79,7 +172,9 @@ impl ShortenUrl for ShortenUrlService {
         &self,
         request: Request<OriginalUrl>,
     ) -> Result<Response<ShortenedUrl>, Status> {
+        let caller = caller_id(&self.signer, &request);
         let original_url = request.into_inner().url;
+        validate_url(&original_url).map_err(UrlShortenerError::InvalidArgument)?;
         let short_code = generate_short_code(5)?;
 
         let shortened_url = url::ActiveModel {
@@ -90,71 +185,154 @@ impl ShortenUrl for ShortenUrlService {
             created_at: Default::default(),
         };
 
-        let saved_url = shortened_url
-            .insert(&*self.db)
+        let saved_url = timed("insert_url", shortened_url.insert(&*self.db))
             .await
             .map_err(UrlShortenerError::from)?;
 
-        info!("Shortened URL: {}", saved_url.id);
-        let mut redis_conn = self
-            .redis
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(|e| {
-                error!("Failed to get Redis connection: {:?}", e);
-                UrlShortenerError::InternalServerError("Redis connection error".into())
-            })?;
+        info!("Shortened URL: {} (caller: {:?})", saved_url.id, caller);
+        // Also replaces any cached "not found" from before the slug existed.
+        self.cache.update(&[short_code], Some(&original_url)).await;
 
-        redis_conn
-            .set_ex::<_, _, ()>(format!("slug:{}", short_code), original_url.clone(), 86_400)
-            .await
-            .map_err(|e| {
-                error!("Failed to cache in Redis: {:?}", e);
-                UrlShortenerError::InternalServerError("Redis cache error".into())
-            })?;
-
-        Ok(Response::new(ShortenedUrl {
-            id: saved_url.id,
-            original_url: saved_url.original,
-            shortened_url: saved_url.shortened,
-            clicks: saved_url.clicks,
-            created_at: saved_url.created_at.to_string(),
-        }))
+        Ok(Response::new(saved_url.into()))
     }
 
     async fn delete_shortened_url(
         &self,
         request: Request<OriginalUrl>,
     ) -> Result<Response<DeleteResponse>, Status> {
+        let caller = caller_id(&self.signer, &request);
         let original_url = request.into_inner().url;
 
-        let delete_result = url::Entity::delete_many()
-            .filter(url::Column::Original.eq(&original_url))
-            .exec(&*self.db)
-            .await
-            .map_err(UrlShortenerError::from)?;
+        // Cache entries are keyed by slug, so look the slugs up before the rows are gone.
+        let slugs = timed(
+            "find_slugs_by_original",
+            url::Entity::find()
+                .select_only()
+                .column(url::Column::Shortened)
+                .filter(url::Column::Original.eq(&original_url))
+                .into_tuple::<String>()
+                .all(&*self.db),
+        )
+        .await
+        .map_err(UrlShortenerError::from)?;
+
+        let delete_result = timed(
+            "delete_by_original",
+            url::Entity::delete_many()
+                .filter(url::Column::Original.eq(&original_url))
+                .exec(&*self.db),
+        )
+        .await
+        .map_err(UrlShortenerError::from)?;
 
         if delete_result.rows_affected == 0 {
             return Err(UrlShortenerError::NotFound.into());
         }
 
-        info!("Deleted {} URL(s)", delete_result.rows_affected);
-        let mut redis_conn = self
-            .redis
-            .get_multiplexed_async_connection()
+        info!(
+            "Deleted {} URL(s) (caller: {:?})",
+            delete_result.rows_affected, caller
+        );
+        self.cache.update(&slugs, None).await;
+
+        Ok(Response::new(DeleteResponse {
+            message: "URL deleted successfully".to_string(),
+            success: true,
+        }))
+    }
+
+    async fn list_shortened_urls(
+        &self,
+        request: Request<ListUrlsRequest>,
+    ) -> Result<Response<ListUrlsResponse>, Status> {
+        let ListUrlsRequest {
+            page_size,
+            page_token,
+        } = request.into_inner();
+        let page_size = match page_size as u64 {
+            0 => DEFAULT_PAGE_SIZE,
+            size => size.min(MAX_PAGE_SIZE),
+        };
+        let after_id = match page_token.as_str() {
+            "" => 0,
+            token => token
+                .parse::<i32>()
+                .map_err(|_| UrlShortenerError::InvalidArgument("Invalid page token".into()))?,
+        };
+
+        // Fetch one extra row to learn whether another page follows.
+        let mut rows = self
+            .list_urls(after_id, page_size + 1)
             .await
-            .map_err(|e| {
-                error!("Failed to get Redis connection: {:?}", e);
-                UrlShortenerError::InternalServerError("Redis connection error".into())
-            })?;
+            .map_err(UrlShortenerError::from)?;
+
+        let next_page_token = if rows.len() as u64 > page_size {
+            rows.truncate(page_size as usize);
+            rows.last()
+                .map(|row| row.id.to_string())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        Ok(Response::new(ListUrlsResponse {
+            urls: rows.into_iter().map(ShortenedUrl::from).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn get_shortened_url(
+        &self,
+        request: Request<Slug>,
+    ) -> Result<Response<ShortenedUrl>, Status> {
+        let slug = request.into_inner().slug;
+        let model = self.find_by_slug(&slug).await?;
 
-        redis_conn
-            .del::<_, ()>(format!("slug:{}", original_url))
+        Ok(Response::new(model.into()))
+    }
+
+    async fn update_shortened_url(
+        &self,
+        request: Request<UpdateUrlRequest>,
+    ) -> Result<Response<ShortenedUrl>, Status> {
+        let caller = caller_id(&self.signer, &request);
+        let UpdateUrlRequest { slug, url } = request.into_inner();
+        validate_url(&url).map_err(UrlShortenerError::InvalidArgument)?;
+
+        let mut model = self.find_by_slug(&slug).await?.into_active_model();
+        model.original = Set(url.clone());
+        let updated = timed("update_url", model.update(&*self.db))
             .await
-            .map_err(|e| {
-                error!("Failed to delete cache entry from Redis: {:?}", e);
-                UrlShortenerError::InternalServerError("Redis deletion error".into())
-            })?;
+            .map_err(UrlShortenerError::from)?;
+
+        info!("Updated URL `{}` (caller: {:?})", slug, caller);
+        self.cache.update(&[slug], Some(&url)).await;
+
+        Ok(Response::new(updated.into()))
+    }
+
+    async fn delete_shortened_url_by_slug(
+        &self,
+        request: Request<Slug>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let caller = caller_id(&self.signer, &request);
+        let slug = request.into_inner().slug;
+
+        let delete_result = timed(
+            "delete_by_slug",
+            url::Entity::delete_many()
+                .filter(url::Column::Shortened.eq(&slug))
+                .exec(&*self.db),
+        )
+        .await
+        .map_err(UrlShortenerError::from)?;
+
+        if delete_result.rows_affected == 0 {
+            return Err(UrlShortenerError::NotFound.into());
+        }
+
+        info!("Deleted URL `{}` (caller: {:?})", slug, caller);
+        self.cache.update(&[slug], None).await;
 
         Ok(Response::new(DeleteResponse {
             message: "URL deleted successfully".to_string(),
@@ -165,24 +343,98 @@ impl ShortenUrl for ShortenUrlService {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    unsafe {
-        env::set_var("RUST_LOG", "info");
-    }
-
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+    let config = Config::load(&ServiceDefaults {
+        name: "shortener_service",
+        listen_addr: "0.0.0.0:50051",
+        metrics_addr: "0.0.0.0:9101",
+    })?;
+    let _telemetry =
+        telemetry::init(&config.telemetry).context("Failed to initialise telemetry")?;
+    let metrics = prometheus::install()?;
+    let shutdown = Shutdown::install(config.server.shutdown_grace_period());
+    let metrics_addr = config.server.metrics_addr;
 
-    let db = connect_db().await.context("Database connection failed")?;
-    let redis = connect_redis().await.context("Redis connection failed")?;
+    let databases = connect_databases(&config.database)
+        .await
+        .context("Database connection failed")?;
+    let db = databases.primary.clone();
+    let redis = connect_redis(&config.redis)
+        .await
+        .context("Redis connection failed")?;
 
-    let addr = "0.0.0.0:50051".parse()?;
-    let service = ShortenUrlService::new(db.clone(), redis.clone());
+    let addr = config.server.listen_addr;
+    let cache = CacheWriter::new(db.clone(), redis.clone(), &config);
+    tokio::spawn(cache.clone().reconcile());
+    let service = ShortenUrlService::new(
+        databases.clone(),
+        cache,
+        IdentitySigner::new(&config.identity),
+    );
 
     info!("🚀 gRPC server listening on {}", addr);
 
-    Server::builder()
+    let mut readiness = Readiness::new()
+        .draining(&shutdown)
+        .postgres(db.clone())
+        .redis_cache(redis.clone());
+    if databases.has_replica() {
+        readiness = readiness.postgres_replica(databases.replica.clone());
+    }
+    let (reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(report_health(reporter, readiness.clone()));
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(echourl::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build_v1()
+        .context("Failed to build reflection service")?;
+    let admin = prometheus::router(metrics).merge(health::router(readiness));
+
+    // gRPC-Web lets browser clients call the service over HTTP/1.1.
+    let server = Server::builder()
+        .layer(
+            TraceLayer::new_for_grpc()
+                .make_span_with(trace_context::grpc_span)
+                .on_response(telemetry::on_grpc_response),
+        )
+        .accept_http1(true)
+        .layer(config.cors.grpc_web_layer())
+        .layer(MapRequestLayer::new(identity::strip_from_grpc_web))
+        .layer(GrpcWebLayer::new())
+        .add_service(health_service)
+        .add_service(reflection)
         .add_service(ShortenUrlServer::new(service))
-        .serve(addr)
-        .await?;
+        .serve_with_shutdown(addr, shutdown.requested());
 
+    tokio::try_join!(
+        shutdown.drain("gRPC server", async {
+            server.await.context("gRPC server error")
+        }),
+        shutdown.drain(
+            "Metrics server",
+            prometheus::serve(metrics_addr, admin, &shutdown)
+        ),
+    )?;
     Ok(())
 }
+
+/// Mirrors dependency readiness into `grpc.health.v1.Health`, so that clients stop routing
+/// to an instance that has lost Postgres.
+async fn report_health(mut reporter: HealthReporter, readiness: Readiness) {
+    let service = <ShortenUrlServer<ShortenUrlService> as NamedService>::NAME;
+    let mut previous = None;
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (ready, checks) = readiness.run().await;
+        let status = match ready {
+            true => ServingStatus::Serving,
+            false => ServingStatus::NotServing,
+        };
+        if previous != Some(status) {
+            info!("Health is now {:?}: {:?}", status, checks);
+            previous = Some(status);
+        }
+        reporter.set_service_status("", status).await;
+        reporter.set_service_status(service, status).await;
+    }
+}